@@ -1,15 +1,23 @@
-use glfw::{Action, Context, Glfw, Key, OpenGlProfileHint, SwapInterval, Window, WindowEvent, WindowHint, WindowMode, FAIL_ON_ERRORS};
+use glfw::{Action, Context, Glfw, Key, Modifiers, MouseButton, OpenGlProfileHint, SwapInterval, Window, WindowEvent, WindowHint, WindowMode, FAIL_ON_ERRORS};
 use libloading::Library;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::mpsc::Receiver;
 use std::thread_local;
 
-use waved_core::state::{AudioFile, State};
+use waved_core::command;
+use waved_core::editor;
+use waved_core::history::History;
+use waved_core::peaks::PeakPyramid;
+use waved_core::state::{AudioFile, LoadStage, Loading, State, View};
 use waved_core::log::Logger;
-use waved_sndfile::io::samples_from_file;
-use waved_sndfile::playback::create_audio_thread;
+use waved_sndfile::dither::Dither;
+use waved_sndfile::io::{samples_to_file, LoadError, RawLayout, WriteOptions};
+use waved_sndfile::loader::{LoadEvent, Loader};
+use waved_sndfile::playback::{create_transport, CpalBackend, LoopRegion, NullBackend, OutputConfig, Transport};
 
 use crate::cli::CommandLineArgs;
 
@@ -20,6 +28,27 @@ const GUILIB_FILENAME: &'static str = "libwaved_gui.so";
 #[cfg(target_os = "windows")]
 const GUILIB_FILENAME: &'static str = "waved_gui.dll";
 
+/// Frames whose peaks are rescanned per frame drawn, after an edit left them approximate.
+const REFINE_FRAMES: usize = 1 << 20;
+
+/// What the editor makes of a key that doesn't type a character, those come as `Char` events.
+fn editor_key(key: Key, mods: Modifiers) -> Option<editor::Key> {
+    if mods.contains(Modifiers::Control) && (Key::A as i32..=Key::Z as i32).contains(&(key as i32)) {
+        return Some(editor::Key::Ctrl((b'a' + (key as i32 - Key::A as i32) as u8) as char));
+    }
+    match key {
+        Key::Escape => Some(editor::Key::Escape),
+        Key::Enter | Key::KpEnter => Some(editor::Key::Enter),
+        Key::Backspace => Some(editor::Key::Backspace),
+        Key::Tab => Some(editor::Key::Tab),
+        Key::Up => Some(editor::Key::Up),
+        Key::Down => Some(editor::Key::Down),
+        Key::Left => Some(editor::Key::Left),
+        Key::Right => Some(editor::Key::Right),
+        _ => None,
+    }
+}
+
 fn dylib_path(lib_filename: &str) -> PathBuf {
     std::env::current_exe().unwrap()
         .parent().unwrap()
@@ -89,6 +118,10 @@ pub struct App {
     events: Receiver<(f64, WindowEvent)>,
     state: RefCell<State>,
     logger: RefCell<Logger>,
+    transport: Transport,
+    loader: RefCell<Option<Loader>>,
+    /// Frame where the mouse was pressed, while it's being dragged to select.
+    drag_start: Cell<Option<usize>>,
 }
 
 thread_local! {
@@ -101,11 +134,11 @@ impl App {
         #[cfg(feature = "live-reload")]
         clean_reloaded_dylib();
 
-        let state = Default::default();
+        let mut state = State::default();
         let gui = Library::new(dylib_load_path(GUILIB_FILENAME))
             .expect("Failed to load core library.");
 
-        let logger = Logger::new();
+        let mut logger = Logger::new();
 
         let mut glfw = glfw::init(FAIL_ON_ERRORS).unwrap();
 
@@ -117,7 +150,10 @@ impl App {
             .expect("Failed to create a window.");
 
         window.set_key_polling(true);
+        window.set_char_polling(true);
         window.set_drag_and_drop_polling(true);
+        window.set_mouse_button_polling(true);
+        window.set_cursor_pos_polling(true);
 
         // Allow rendering while resizing due to wait_events / poll_events
         // locking the main loop on macOS (see https://github.com/glfw/glfw/issues/1).
@@ -127,7 +163,15 @@ impl App {
         gl::load_with(|symbol| window.get_proc_address(symbol));
         glfw.set_swap_interval(SwapInterval::Sync(1)); // Enable vsync
 
-        create_audio_thread(1024);
+        let transport = match CpalBackend::new(1024, Dither::Tpdf) {
+            Ok(backend) => create_transport(backend),
+            Err(err) => {
+                // Keep the transport running without sound so that the rest of the app behaves the same.
+                logger.log(err);
+                state.message = logger.last().map(String::from);
+                create_transport(NullBackend::realtime(OutputConfig { channels: 2, sample_rate: 48000 }, 1024))
+            },
+        };
 
         Self {
             gui: RefCell::new(gui),
@@ -136,6 +180,9 @@ impl App {
             events,
             state: RefCell::new(state),
             logger: RefCell::new(logger),
+            transport,
+            loader: RefCell::new(None),
+            drag_start: Cell::new(None),
         }
     }
 
@@ -152,8 +199,8 @@ impl App {
     }
 
     pub fn run(&self, args: CommandLineArgs) {
-        if !args.files.is_empty() {
-            println!("Files {:?}", args.files);
+        if let Some(arg) = args.files.first() {
+            self.start_loading(arg.path.clone(), arg.raw);
         }
 
         #[cfg(feature = "live-reload")]
@@ -176,6 +223,9 @@ impl App {
                 }
             }
 
+            self.poll_loader();
+            self.refine_peaks();
+            self.state.borrow_mut().playback = self.transport.position();
             self.render_gui();
 
             self.glfw.borrow_mut().poll_events();
@@ -203,26 +253,60 @@ impl App {
 
     fn process_event(&self, event: WindowEvent) {
         match event {
-            WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
-                self.window.borrow_mut().set_should_close(true)
+            WindowEvent::Key(Key::Escape, _, Action::Press, _) if self.loader.borrow().is_some() => {
+                self.cancel_loading();
             },
-            WindowEvent::Key(Key::O, _, Action::Press, _) => {
-                let result = nfd::dialog()
-                    .filter("wav").open()
-                    .expect("Failed to open file dialog.");
-
-                match result {
-                    nfd::Response::Okay(filename) => {
-                        self.load_file(filename);
+            WindowEvent::Key(Key::S, _, Action::Press, mods) if mods.contains(Modifiers::Control | Modifiers::Shift) => {
+                self.save_dialog();
+            },
+            WindowEvent::Key(Key::S, _, Action::Press, mods) if mods.contains(Modifiers::Control) => {
+                let file = self.state.borrow().current_file.as_ref().map(|f| (f.filename.clone(), f.headerless));
+                match file {
+                    // Raw PCM has no header to be saved back with, it needs a new name.
+                    Some((_, true)) => self.save_dialog(),
+                    Some((filename, false)) => {
+                        self.save_file(filename);
                     },
-                    nfd::Response::OkayMultiple(_) => panic!("Should only be able to select a single file."),
-                    nfd::Response::Cancel => {},
+                    None => {},
                 }
             },
-            WindowEvent::Key(Key::Space, _, Action::Press, _) => {
+            WindowEvent::Key(key, _, Action::Press, mods)
+                if mods.contains(Modifiers::Control) && (Key::Num1 as i32..=Key::Num9 as i32).contains(&(key as i32)) => {
+                let channel = (key as i32 - Key::Num1 as i32) as usize;
+                if let Some(selection) = &mut self.state.borrow_mut().selection {
+                    selection.toggle_channel(channel);
+                }
+            },
+            WindowEvent::Key(key, _, Action::Press | Action::Repeat, mods) => {
+                if let Some(key) = editor_key(key, mods) {
+                    self.handle_key(key);
+                }
+            },
+            WindowEvent::Char(c) => self.handle_key(editor::Key::Char(c)),
+            WindowEvent::MouseButton(MouseButton::Button1, Action::Press, mods) => {
+                let (x, _) = self.window.borrow().get_cursor_pos();
+                let frame = self.frame_at(x);
+                let mut state = self.state.borrow_mut();
+                if mods.contains(Modifiers::Shift) {
+                    state.extend_selection(frame);
+                } else {
+                    state.set_cursor(frame);
+                    state.clear_selection();
+                    self.drag_start.set(Some(state.cursor));
+                }
+            },
+            WindowEvent::MouseButton(MouseButton::Button1, Action::Release, _) => {
+                self.drag_start.set(None);
+                self.update_loop();
+            },
+            WindowEvent::CursorPos(x, _) => {
+                if let Some(start) = self.drag_start.get() {
+                    let frame = self.frame_at(x);
+                    self.state.borrow_mut().select(start.min(frame)..start.max(frame));
+                }
             },
             WindowEvent::FileDrop(files) => {
-                if files.len() > 0 {
+                if files.len() > 0 && self.can_replace_file() {
                     self.load_file(&files[0]);
                 }
             }
@@ -230,17 +314,241 @@ impl App {
         }
     }
 
-    fn load_file<P: AsRef<Path> + Into<PathBuf>>(&self, filename: P) {
-        match samples_from_file(&filename) {
-            Ok((samples, channels, sample_rate)) => {
-                self.state.borrow_mut().current_file = Some(AudioFile {
-                    filename: filename.into(),
-                    samples,
-                    channels,
-                    sample_rate
-                })
+    fn handle_key(&self, key: editor::Key) {
+        let action = editor::handle_key(&mut self.state.borrow_mut(), key);
+        if let Some(action) = action {
+            self.perform(action);
+        }
+    }
+
+    fn perform(&self, action: editor::Action) {
+        match action {
+            editor::Action::FileEdited => {
+                if let Some(file) = self.state.borrow().current_file.clone() {
+                    self.transport.replace(file);
+                }
+                self.update_loop();
+            },
+            editor::Action::LoopChanged => self.update_loop(),
+            editor::Action::TogglePlayback => self.transport.toggle(),
+            editor::Action::Open => self.open_dialog(),
+            editor::Action::Command(line) => {
+                let result = command::run(&mut self.state.borrow_mut(), &line);
+                match result {
+                    Ok(Some(action)) => self.perform(action),
+                    Ok(None) => {},
+                    Err(err) => self.log(err),
+                }
+            },
+            editor::Action::Write { filename, quit } => {
+                let filename = match filename {
+                    Some(filename) => filename,
+                    None => match &self.state.borrow().current_file {
+                        Some(file) => file.filename.clone(),
+                        None => return,
+                    },
+                };
+                if self.save_file(filename) && quit {
+                    self.window.borrow_mut().set_should_close(true);
+                }
+            },
+            editor::Action::Edit(filename) => self.load_file(filename),
+            editor::Action::Quit => self.window.borrow_mut().set_should_close(true),
+        }
+    }
+
+    /// Whether the current file can be replaced by another one, it can't while it has unsaved
+    /// changes. `:e!` is the way to throw them away.
+    fn can_replace_file(&self) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.modified {
+            state.message = Some("No write since last change, save it or open the file with :e!".to_string());
+        }
+        !state.modified
+    }
+
+    fn open_dialog(&self) {
+        if !self.can_replace_file() {
+            return;
+        }
+        let result = nfd::dialog()
+            .filter("wav,w64,flac,aif,aiff,aifc,ogg,oga,mp3").open()
+            .expect("Failed to open file dialog.");
+
+        match result {
+            nfd::Response::Okay(filename) => {
+                self.load_file(filename);
+            },
+            nfd::Response::OkayMultiple(_) => panic!("Should only be able to select a single file."),
+            nfd::Response::Cancel => {},
+        }
+    }
+
+    fn save_dialog(&self) {
+        let result = nfd::dialog_save()
+            .filter("wav,w64,flac,aif,aiff,aifc").open()
+            .expect("Failed to open file dialog.");
+
+        if let nfd::Response::Okay(filename) = result {
+            self.save_file(filename);
+        }
+    }
+
+    /// Frame of the current file under the horizontal position `x` of the window.
+    fn frame_at(&self, x: f64) -> usize {
+        let (width, _) = self.window.borrow().get_size();
+        let state = self.state.borrow();
+        let frames = state.current_file.as_ref().map_or(0, |file| file.frames());
+        state.view.frame_at(x as f32 / width as f32, frames)
+    }
+
+    fn load_file<P: AsRef<Path>>(&self, filename: P) {
+        self.start_loading(filename.as_ref().to_path_buf(), None);
+    }
+
+    /// Loads a file on a worker thread, replacing the one being loaded if any. The current file
+    /// stays up until the new one is done.
+    fn start_loading(&self, filename: PathBuf, layout: Option<RawLayout>) {
+        *self.loader.borrow_mut() = Some(Loader::spawn(filename.clone(), layout));
+        self.state.borrow_mut().loading = Some(Loading {
+            filename,
+            stage: LoadStage::Decoding,
+            progress: 0.0,
+            frames: 0,
+            peaks: PeakPyramid::default(),
+        });
+    }
+
+    fn cancel_loading(&self) {
+        // Dropping the loader stops it
+        *self.loader.borrow_mut() = None;
+        let mut state = self.state.borrow_mut();
+        state.loading = None;
+        state.message = Some("Loading cancelled".to_string());
+    }
+
+    fn poll_loader(&self) {
+        let events: Vec<LoadEvent> = match &*self.loader.borrow() {
+            Some(loader) => loader.events().collect(),
+            None => return,
+        };
+
+        for event in events {
+            let mut state = self.state.borrow_mut();
+            let loading = match &mut state.loading {
+                Some(loading) => loading,
+                None => return,
+            };
+            match event {
+                LoadEvent::Progress(stage, progress) => {
+                    loading.stage = stage;
+                    loading.progress = progress;
+                },
+                LoadEvent::Decoded { frames, channels } => {
+                    loading.frames = frames;
+                    loading.peaks = PeakPyramid::new(channels);
+                },
+                LoadEvent::Peaks(peaks) => loading.peaks.append(peaks),
+                LoadEvent::Done(result) => {
+                    let peaks = state.loading.take().map(|l| l.peaks).unwrap_or_default();
+                    drop(state);
+                    *self.loader.borrow_mut() = None;
+                    self.set_file(*result, peaks);
+                    return;
+                },
+            }
+        }
+    }
+
+    fn refine_peaks(&self) {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        if let Some(file) = &state.current_file {
+            state.peaks.refine(&file.samples, REFINE_FRAMES);
+        }
+    }
+
+    fn set_file(&self, result: Result<AudioFile, LoadError>, peaks: PeakPyramid) {
+        let file = match result {
+            Ok(file) => file,
+            Err(err) => {
+                self.log(&err);
+                match err.into_recovered() {
+                    Some(file) => file,
+                    None => return,
+                }
+            },
+        };
+
+        let file = Arc::new(file);
+        self.transport.load(file.clone());
+        let mut state = self.state.borrow_mut();
+        state.current_file = Some(file);
+        state.peaks = peaks;
+        state.view = View::default();
+        state.cursor = 0;
+        state.selection = None;
+        state.history = History::default();
+        state.modified = false;
+        drop(state);
+        self.update_loop();
+    }
+
+    /// Saves the current file, returning whether it was.
+    fn save_file<P: AsRef<Path> + Into<PathBuf>>(&self, filename: P) -> bool {
+        let file = match &self.state.borrow().current_file {
+            Some(file) => file.clone(),
+            None => return false,
+        };
+
+        match samples_to_file(&filename, &file, &WriteOptions::new(file.bit_depth)) {
+            Ok(()) => {
+                let mut state = self.state.borrow_mut();
+                state.history.mark_saved();
+                state.modified = false;
+                if filename.as_ref() != file.filename {
+                    // The transport keeps playing its own copy, it doesn't care about the name.
+                    state.current_file = Some(Arc::new(AudioFile {
+                        filename: filename.into(),
+                        headerless: false,
+                        ..(*file).clone()
+                    }));
+                }
+                true
+            },
+            Err(err) => {
+                self.log(err);
+                false
             },
-            Err(err) => { dbg!(err); },
         }
     }
+
+    fn log<E: Error>(&self, err: E) {
+        let mut logger = self.logger.borrow_mut();
+        logger.log(err);
+        self.state.borrow_mut().message = logger.last().map(String::from);
+    }
+
+    fn update_loop(&self) {
+        const CROSSFADE_SECONDS: f32 = 0.005;
+
+        let state = self.state.borrow();
+        let region = match &state.current_file {
+            Some(file) if state.looping => {
+                // Prefer the selection, then the loop stored in the file, samples are often
+                // delivered with one.
+                let (start, end) = match &state.selection {
+                    Some(selection) => (selection.start, selection.end),
+                    None => file.loop_points.unwrap_or((0, file.frames())),
+                };
+                Some(LoopRegion {
+                    start,
+                    end,
+                    crossfade: (file.sample_rate as f32 * CROSSFADE_SECONDS) as usize,
+                })
+            },
+            _ => None,
+        };
+        self.transport.set_loop(region);
+    }
 }
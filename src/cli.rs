@@ -1,14 +1,45 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use waved_sndfile::io::{RawLayout, RawLayoutError};
+
+/// A file to open, with the layout to read it with when it's raw PCM.
+pub struct FileArg {
+    pub path: PathBuf,
+    pub raw: Option<RawLayout>,
+}
+
+/// Raw files are given as `path@layout`, like `dump.bin@i16le,2ch,48000`. Existing files are
+/// taken as is, even when their name contains an `@`.
+impl FromStr for FileArg {
+    type Err = RawLayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rfind('@') {
+            Some(index) if !PathBuf::from(s).exists() => Ok(FileArg {
+                path: PathBuf::from(&s[..index]),
+                raw: Some(s[index + 1..].parse()?),
+            }),
+            _ => Ok(FileArg { path: PathBuf::from(s), raw: None }),
+        }
+    }
+}
+
 pub struct CommandLineArgs {
-    pub files: Vec<String>,
+    pub files: Vec<FileArg>,
 }
 
 pub fn parse_commandline() -> CommandLineArgs {
     let matches = clap::App::new("myprog")
-        .arg(clap::Arg::with_name("files").multiple(true))
+        .arg(clap::Arg::with_name("files")
+            .multiple(true)
+            .help("Files to open, raw PCM is read with FILE@FORMAT,CHANNELS,RATE[,planar] \
+                   such as dump.bin@i16le,2ch,48000")
+            .validator(|s| s.parse::<FileArg>().map(|_| ()).map_err(|err| err.to_string())))
         .get_matches();
 
     let files = matches.values_of("files").unwrap_or_default()
-        .map(|s| s.to_string())
+        .map(|s| s.parse().unwrap())
         .collect();
 
     CommandLineArgs { files }
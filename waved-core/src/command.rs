@@ -0,0 +1,275 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::editor::{self, Action};
+use crate::peaks::Peak;
+use crate::state::State;
+
+/// Names of the commands, with how short each can be abbreviated.
+const COMMANDS: &[(&str, usize)] = &[
+    ("edit", 1),
+    ("gain", 1),
+    ("normalize", 4),
+    ("quit", 1),
+    ("set", 2),
+    ("write", 1),
+    ("wq", 2),
+];
+
+/// Options of `:set`, as they're completed.
+const OPTIONS: &[&str] = &["loop", "noloop", "silence=", "tempo="];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Saves the file, under another name when given.
+    Write(Option<PathBuf>),
+    WriteQuit,
+    /// Opens another file, even with unsaved changes when forced.
+    Edit { filename: PathBuf, force: bool },
+    /// Quits, even with unsaved changes when forced.
+    Quit { force: bool },
+    /// Gain in dB, applied to the selection or the whole file.
+    Gain(f32),
+    /// Peak level in dBFS the selection or the whole file is brought to.
+    Normalize(f32),
+    /// Option and the value it's set to, if any.
+    Set(String, Option<String>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    InvalidArgument(String),
+    NoFile,
+    Modified,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "Not an editor command: {}", name),
+            CommandError::InvalidArgument(message) => write!(f, "{}", message),
+            CommandError::NoFile => write!(f, "No file open"),
+            CommandError::Modified => write!(f, "No write since last change (add ! to override)"),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Full name of the command `name` abbreviates.
+fn command_name(name: &str) -> Option<&'static str> {
+    COMMANDS.iter()
+        .find(|(command, shortest)| name.len() >= *shortest && command.starts_with(name))
+        .map(|(command, _)| *command)
+}
+
+/// Parses a level in decibels, with or without its unit.
+fn parse_decibels(argument: &str) -> Result<f32, CommandError> {
+    let number = argument.strip_suffix("dB").or_else(|| argument.strip_suffix("db")).unwrap_or(argument);
+    number.trim().parse().map_err(|_| CommandError::InvalidArgument(format!("Invalid level: {}", argument)))
+}
+
+/// Parses a command line, without its colon.
+pub fn parse(line: &str) -> Result<Command, CommandError> {
+    let line = line.trim();
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let (name, force) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let path = || (!argument.is_empty()).then(|| PathBuf::from(argument));
+
+    match command_name(name) {
+        Some("write") => Ok(Command::Write(path())),
+        Some("wq") => Ok(Command::WriteQuit),
+        Some("edit") => path()
+            .map(|filename| Command::Edit { filename, force })
+            .ok_or_else(|| CommandError::InvalidArgument("Argument required".to_string())),
+        Some("quit") => Ok(Command::Quit { force }),
+        Some("gain") => Ok(Command::Gain(parse_decibels(argument)?)),
+        Some("normalize") if argument.is_empty() => Ok(Command::Normalize(0.0)),
+        Some("normalize") => Ok(Command::Normalize(parse_decibels(argument)?)),
+        Some("set") => {
+            let (option, value) = match argument.split_once('=') {
+                Some((option, value)) => (option, Some(value.to_string())),
+                None => (argument, None),
+            };
+            Ok(Command::Set(option.to_string(), value))
+        },
+        _ => Err(CommandError::Unknown(line.to_string())),
+    }
+}
+
+/// Applies `command` to `state`, leaving the rest to the application.
+pub fn execute(state: &mut State, command: Command) -> Result<Option<Action>, CommandError> {
+    match command {
+        Command::Write(filename) => {
+            state.current_file.as_ref().ok_or(CommandError::NoFile)?;
+            Ok(Some(Action::Write { filename, quit: false }))
+        },
+        Command::WriteQuit => {
+            state.current_file.as_ref().ok_or(CommandError::NoFile)?;
+            Ok(Some(Action::Write { filename: None, quit: true }))
+        },
+        Command::Edit { force: false, .. } if state.modified => Err(CommandError::Modified),
+        Command::Edit { filename, .. } => Ok(Some(Action::Edit(filename))),
+        Command::Quit { force } if state.modified && !force => Err(CommandError::Modified),
+        Command::Quit { .. } => Ok(Some(Action::Quit)),
+        Command::Gain(decibels) => amplify(state, 10f32.powf(decibels / 20.0)),
+        Command::Normalize(decibels) => {
+            let file = state.current_file.clone().ok_or(CommandError::NoFile)?;
+            let (range, mask) = editor::selected(state);
+            let peak = (0..file.channels as usize)
+                .filter(|&c| mask[c])
+                .filter_map(|c| {
+                    // The peaks of the whole file are exact once refined, those of part of it
+                    // may cover more.
+                    if range == (0..file.frames()) && state.peaks.is_exact() {
+                        state.peaks.peak(c, range.clone())
+                    } else {
+                        Peak::scan(&file.samples, c, range.clone())
+                    }
+                })
+                .fold(0.0f32, |loudest, peak| loudest.max(peak.max).max(-peak.min));
+            if peak == 0.0 {
+                return Err(CommandError::InvalidArgument("Nothing to normalize, it's silent".to_string()));
+            }
+            amplify(state, 10f32.powf(decibels / 20.0) / peak)
+        },
+        Command::Set(option, value) => set(state, &option, value.as_deref()),
+    }
+}
+
+/// Parses and runs a command line.
+pub fn run(state: &mut State, line: &str) -> Result<Option<Action>, CommandError> {
+    execute(state, parse(line)?)
+}
+
+fn amplify(state: &mut State, gain: f32) -> Result<Option<Action>, CommandError> {
+    let file = state.current_file.clone().ok_or(CommandError::NoFile)?;
+    let (range, mask) = editor::selected(state);
+    let samples = file.samples.amplify(range.clone(), gain, &mask);
+    editor::apply_edit(state, file.edited(samples, range.start, range.len(), range.len()), range.start, range.len(), range.len());
+    Ok(Some(Action::FileEdited))
+}
+
+fn set(state: &mut State, option: &str, value: Option<&str>) -> Result<Option<Action>, CommandError> {
+    let number = || {
+        value
+            .and_then(|value| value.parse::<f32>().ok())
+            .filter(|&value| value > 0.0)
+            .ok_or_else(|| CommandError::InvalidArgument(format!("Invalid value for {}: {}", option, value.unwrap_or(""))))
+    };
+    match option {
+        "" => {
+            let settings = &state.settings;
+            state.message = Some(format!("tempo={} silence={} {}", settings.tempo, settings.min_silence,
+                if state.looping { "loop" } else { "noloop" }));
+            Ok(None)
+        },
+        "tempo" => {
+            state.settings.tempo = number()?;
+            Ok(None)
+        },
+        "silence" => {
+            state.settings.min_silence = number()?;
+            Ok(None)
+        },
+        "loop" | "noloop" => {
+            state.looping = option == "loop";
+            Ok(Some(Action::LoopChanged))
+        },
+        _ => Err(CommandError::InvalidArgument(format!("Unknown option: {}", option))),
+    }
+}
+
+/// Command lines `line` can be completed to, sorted.
+pub fn complete(line: &str) -> Vec<String> {
+    let (name, argument) = match line.split_once(' ') {
+        Some((name, argument)) => (name, argument),
+        None => {
+            return COMMANDS.iter()
+                .filter(|(command, _)| command.starts_with(line))
+                .map(|(command, _)| command.to_string())
+                .collect();
+        },
+    };
+
+    match command_name(name.trim_end_matches('!')) {
+        Some("edit" | "write") => complete_path(argument)
+            .into_iter()
+            .map(|path| format!("{} {}", name, path))
+            .collect(),
+        Some("set") => OPTIONS.iter()
+            .filter(|option| option.starts_with(argument))
+            .map(|option| format!("{} {}", name, option))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Paths `partial` can be completed to, directories ending with a slash.
+fn complete_path(partial: &str) -> Vec<String> {
+    let (directory, prefix) = match partial.rfind('/') {
+        Some(index) => partial.split_at(index + 1),
+        None => ("", partial),
+    };
+    let entries = match fs::read_dir(if directory.is_empty() { "." } else { directory }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut paths: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files only when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", directory, name, slash))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_complete() {
+        assert_eq!(parse("w"), Ok(Command::Write(None)));
+        assert_eq!(parse("write out.wav"), Ok(Command::Write(Some(PathBuf::from("out.wav")))));
+        assert_eq!(parse("q!"), Ok(Command::Quit { force: true }));
+        assert_eq!(parse("e! take.wav"), Ok(Command::Edit { filename: PathBuf::from("take.wav"), force: true }));
+        assert_eq!(parse("gain -3dB"), Ok(Command::Gain(-3.0)));
+        assert_eq!(parse("norm"), Ok(Command::Normalize(0.0)));
+        assert_eq!(parse("set tempo=90"), Ok(Command::Set("tempo".to_string(), Some("90".to_string()))));
+        assert_eq!(parse("nor"), Err(CommandError::Unknown("nor".to_string())));
+        assert!(parse("gain loud").is_err());
+
+        // Unsaved changes aren't thrown away unless asked to
+        let mut state = State { modified: true, ..Default::default() };
+        assert_eq!(run(&mut state, "e take.wav"), Err(CommandError::Modified));
+        assert_eq!(run(&mut state, "e! take.wav"), Ok(Some(Action::Edit(PathBuf::from("take.wav")))));
+
+        assert_eq!(complete("w"), ["write", "wq"]);
+        assert_eq!(complete("se lo"), ["se loop"]);
+
+        let directory = std::env::temp_dir().join("waved-complete");
+        fs::create_dir_all(directory.join("takes")).unwrap();
+        fs::write(directory.join("take1.wav"), []).unwrap();
+        let line = |name: &str| format!("e {}/{}", directory.display(), name);
+        assert_eq!(complete(&line("ta")), [line("take1.wav"), line("takes/")]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,657 @@
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::command;
+use crate::history::{Delta, History};
+use crate::motion;
+use crate::samples::SampleStore;
+use crate::state::{AudioFile, State, View};
+
+/// What keys do, the way vim does it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// Keys move the cursor, and run operators over the frames they move across.
+    #[default]
+    Normal,
+    /// Motions select from where visual mode was entered.
+    Visual,
+    /// Keys are typed into the command line.
+    Command,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Visual => "VISUAL",
+            Mode::Command => "COMMAND",
+        }
+    }
+}
+
+/// Key pressed, whatever the window it was pressed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Char(char),
+    /// A letter with Control held.
+    Ctrl(char),
+    Escape,
+    Enter,
+    Backspace,
+    Tab,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// What a key asks of the application, which the editor can't do by itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// `current_file` was edited, playback has to pick the changes up.
+    FileEdited,
+    /// Looping was toggled or the looped frames changed.
+    LoopChanged,
+    TogglePlayback,
+    Open,
+    /// Command line entered, without its colon, for `command::run`.
+    Command(String),
+    /// Saves the file, under another name when given, then quits if asked to.
+    Write { filename: Option<PathBuf>, quit: bool },
+    Edit(PathBuf),
+    Quit,
+}
+
+/// Command lines remembered.
+const HISTORY_LENGTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Delete,
+    Yank,
+}
+
+/// Fraction of the view moved across by `h` and `l`.
+const STEP: f32 = 0.01;
+/// Fraction of the view moved across by `b` and `w`.
+const WORD: f32 = 0.1;
+
+/// Where a key moves the cursor to. Those that find something in the file go forward when
+/// `true`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    WordBackward,
+    WordForward,
+    Start,
+    End,
+    ZeroCrossing(bool),
+    Transient(bool),
+    Silence(bool),
+    Marker(bool),
+    Seconds(bool),
+    Beats(bool),
+}
+
+impl Motion {
+    fn from_key(prefix: Option<char>, c: char) -> Option<Self> {
+        match (prefix, c) {
+            (None, 'h') => Some(Motion::Left),
+            (None, 'l') => Some(Motion::Right),
+            (None, 'b') => Some(Motion::WordBackward),
+            (None, 'w') => Some(Motion::WordForward),
+            (None, '0') | (Some('g'), 'g') => Some(Motion::Start),
+            (None, '$') | (None, 'G') => Some(Motion::End),
+            (Some(bracket @ ('[' | ']')), c) => {
+                let forward = bracket == ']';
+                match c {
+                    'z' => Some(Motion::ZeroCrossing(forward)),
+                    't' => Some(Motion::Transient(forward)),
+                    's' => Some(Motion::Silence(forward)),
+                    'm' => Some(Motion::Marker(forward)),
+                    _ => None,
+                }
+            },
+            (None, '(' | ')') => Some(Motion::Seconds(c == ')')),
+            (None, '{' | '}') => Some(Motion::Beats(c == '}')),
+            _ => None,
+        }
+    }
+
+    /// Where the cursor lands after moving `count` times, if it can move at all.
+    fn target(self, state: &State, count: usize) -> Option<usize> {
+        let frames = state.frames();
+        let view = state.view.range(frames).len() as f32;
+        let distance = |fraction: f32| ((view * fraction) as usize).max(1).saturating_mul(count);
+        let by = |frames_per: f32, forward: bool| {
+            let distance = (frames_per * count as f32) as usize;
+            if forward {
+                state.cursor.saturating_add(distance).min(frames)
+            } else {
+                state.cursor.saturating_sub(distance)
+            }
+        };
+        let file = state.current_file.as_ref()?;
+        let sample_rate = file.sample_rate as f32;
+        let target = match self {
+            Motion::Left => state.cursor.saturating_sub(distance(STEP)),
+            Motion::Right => state.cursor.saturating_add(distance(STEP)).min(frames),
+            Motion::WordBackward => state.cursor.saturating_sub(distance(WORD)),
+            Motion::WordForward => state.cursor.saturating_add(distance(WORD)).min(frames),
+            Motion::Start => 0,
+            Motion::End => frames,
+            Motion::ZeroCrossing(forward) => {
+                let mut position = None;
+                for _ in 0..count {
+                    match motion::zero_crossing(&file.samples, position.unwrap_or(state.cursor), forward) {
+                        Some(found) => position = Some(found),
+                        None => break,
+                    }
+                }
+                position?
+            },
+            Motion::Transient(forward) => motion::transient(&file.samples, &state.peaks, state.cursor, forward, count)?,
+            Motion::Silence(forward) => {
+                let min_frames = (state.settings.min_silence * sample_rate) as usize;
+                motion::silence_boundary(&state.peaks, frames, min_frames, state.cursor, forward, count)?
+            },
+            Motion::Marker(forward) => motion::marker(&file.markers, state.cursor, forward, count)?,
+            Motion::Seconds(forward) => by(sample_rate, forward),
+            Motion::Beats(forward) => by(sample_rate * 60.0 / state.settings.tempo, forward),
+        };
+        Some(target)
+    }
+}
+
+/// Keys typed so far and what's been made of them.
+#[derive(Clone, Default)]
+pub struct Editor {
+    pub mode: Mode,
+    count: Option<usize>,
+    /// Operator waiting for a motion, with the count typed before it.
+    operator: Option<(Operator, usize)>,
+    /// First key of a two-key command, like `g`.
+    prefix: Option<char>,
+    /// Keys of the command being composed, shown in the status bar.
+    pub pending: String,
+    /// What's been typed after `:` in command mode.
+    pub command_line: String,
+    /// Command lines entered, the last one last.
+    history: Vec<String>,
+    /// Entry of the history being recalled, with what was typed before recalling it.
+    recalled: Option<(usize, String)>,
+    /// Command lines Tab goes through, with the one it's at.
+    completions: Vec<String>,
+    completion: usize,
+    /// End of the selection that stays put in visual mode.
+    anchor: usize,
+    /// Frames last deleted or yanked, for `p` to put back.
+    pub register: Option<SampleStore>,
+}
+
+impl Editor {
+    fn reset(&mut self) {
+        self.count = None;
+        self.operator = None;
+        self.prefix = None;
+        self.pending.clear();
+    }
+
+    /// Puts an older or newer command line of the history in the command line, what was being
+    /// typed coming back past the newest.
+    fn recall(&mut self, older: bool) {
+        let index = match (&self.recalled, older) {
+            (None, true) if !self.history.is_empty() => self.history.len() - 1,
+            (Some((index, _)), true) => index.saturating_sub(1),
+            (Some((index, _)), false) if index + 1 < self.history.len() => index + 1,
+            (Some(_), false) => {
+                if let Some((_, typed)) = self.recalled.take() {
+                    self.command_line = typed;
+                }
+                return;
+            },
+            _ => return,
+        };
+        let typed = match self.recalled.take() {
+            Some((_, typed)) => typed,
+            None => self.command_line.clone(),
+        };
+        self.command_line = self.history[index].clone();
+        self.recalled = Some((index, typed));
+    }
+
+    /// Completes the command line as far as all its completions agree, then goes through
+    /// them one at a time.
+    fn complete(&mut self) {
+        if !self.completions.is_empty() {
+            self.completion = (self.completion + 1) % self.completions.len();
+            self.command_line = self.completions[self.completion].clone();
+            return;
+        }
+
+        let completions = command::complete(&self.command_line);
+        let common = completions.iter().skip(1).fold(completions.first().cloned().unwrap_or_default(), |common, c| {
+            let length = common.chars().zip(c.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+            common[..length].to_string()
+        });
+        if completions.len() == 1 || common.len() > self.command_line.len() {
+            self.command_line = common;
+        } else if !completions.is_empty() {
+            self.command_line = completions[0].clone();
+            self.completions = completions;
+            self.completion = 0;
+        }
+    }
+}
+
+/// Feeds a key to the editor, which updates `state` and says what's left for the application
+/// to do.
+pub fn handle_key(state: &mut State, key: Key) -> Option<Action> {
+    match state.editor.mode {
+        Mode::Command => command_key(state, key),
+        Mode::Normal | Mode::Visual => normal_key(state, key),
+    }
+}
+
+fn command_key(state: &mut State, key: Key) -> Option<Action> {
+    let editor = &mut state.editor;
+    if key != Key::Tab {
+        editor.completions.clear();
+    }
+    match key {
+        Key::Char(c) => editor.command_line.push(c),
+        // Like vim, erasing past the colon leaves the command line
+        Key::Backspace if editor.command_line.pop().is_none() => editor.mode = Mode::Normal,
+        Key::Escape => {
+            editor.command_line.clear();
+            editor.recalled = None;
+            editor.mode = Mode::Normal;
+        },
+        Key::Enter => {
+            editor.mode = Mode::Normal;
+            editor.recalled = None;
+            let line = mem::take(&mut editor.command_line);
+            if line.trim().is_empty() {
+                return None;
+            }
+            if editor.history.last() != Some(&line) {
+                editor.history.push(line.clone());
+                if editor.history.len() > HISTORY_LENGTH {
+                    editor.history.remove(0);
+                }
+            }
+            return Some(Action::Command(line));
+        },
+        Key::Up => editor.recall(true),
+        Key::Down => editor.recall(false),
+        Key::Tab => editor.complete(),
+        _ => {},
+    }
+    None
+}
+
+fn normal_key(state: &mut State, key: Key) -> Option<Action> {
+    let c = match key {
+        Key::Char(c) => c,
+        Key::Escape => return escape(state),
+        Key::Left => return scroll(state, -0.25),
+        Key::Right => return scroll(state, 0.25),
+        Key::Ctrl('r') if state.editor.mode == Mode::Normal => {
+            let count = state.editor.count.take().unwrap_or(1);
+            state.editor.reset();
+            return travel(state, count, History::redo, "Already at newest change");
+        },
+        _ => return None,
+    };
+
+    let editor = &mut state.editor;
+    editor.pending.push(c);
+    // A 0 is only part of a count once one was started, otherwise it's a motion.
+    if let Some(digit) = c.to_digit(10).filter(|&d| d > 0 || editor.count.is_some()) {
+        editor.count = Some(editor.count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+        return None;
+    }
+    let prefix = editor.prefix.take();
+    if prefix.is_none() && matches!(c, 'g' | '[' | ']') {
+        editor.prefix = Some(c);
+        return None;
+    }
+    let count = editor.count.take().unwrap_or(1);
+
+    if let Some(motion) = Motion::from_key(prefix, c) {
+        let action = apply_motion(state, motion, count);
+        state.editor.reset();
+        return action;
+    }
+
+    // Besides motions, only g- and g+ follow a prefix and nothing but a motion follows an operator.
+    // Anything else, including a doubled operator, drops what was being composed.
+    let editor = &mut state.editor;
+    let history_prefix = prefix == Some('g') && matches!(c, '-' | '+');
+    if editor.operator.is_some() || (prefix.is_some() && !history_prefix) {
+        editor.reset();
+        return None;
+    }
+
+    let action = match (editor.mode, c) {
+        (Mode::Normal, 'd' | 'y') => {
+            let operator = if c == 'd' { Operator::Delete } else { Operator::Yank };
+            editor.operator = Some((operator, count));
+            return None;
+        },
+        (Mode::Visual, 'd' | 'x') | (Mode::Normal, 'x') => delete_selection(state),
+        (Mode::Visual, 'y') => yank_selection(state),
+        (_, 'p') => put(state, count, true),
+        (_, 'P') => put(state, count, false),
+        (Mode::Normal, 'v') => {
+            editor.mode = Mode::Visual;
+            editor.anchor = state.cursor;
+            None
+        },
+        (Mode::Visual, 'v') => {
+            editor.mode = Mode::Normal;
+            None
+        },
+        (_, ':') => {
+            editor.mode = Mode::Command;
+            editor.command_line.clear();
+            None
+        },
+        (Mode::Normal, 'u') => travel(state, count, History::undo, "Already at oldest change"),
+        (Mode::Normal, '-') if prefix == Some('g') => travel(state, count, History::earlier, "Already at oldest change"),
+        (Mode::Normal, '+') if prefix == Some('g') => travel(state, count, History::later, "Already at newest change"),
+        (_, ' ') => Some(Action::TogglePlayback),
+        (_, 'L') => {
+            state.looping = !state.looping;
+            Some(Action::LoopChanged)
+        },
+        (_, 'I') => {
+            state.show_metadata = !state.show_metadata;
+            None
+        },
+        (_, 'o') => Some(Action::Open),
+        (_, '=') => zoom(state, 0.5),
+        (_, '-') => zoom(state, 2.0),
+        (_, '_') => {
+            state.view = View::default();
+            None
+        },
+        _ => None,
+    };
+    state.editor.reset();
+    action
+}
+
+fn escape(state: &mut State) -> Option<Action> {
+    let editor = &mut state.editor;
+    if !editor.pending.is_empty() {
+        editor.reset();
+        None
+    } else if editor.mode == Mode::Visual {
+        // The selection stays, to be looped or played
+        editor.mode = Mode::Normal;
+        None
+    } else if state.selection.is_some() {
+        state.clear_selection();
+        Some(Action::LoopChanged)
+    } else {
+        None
+    }
+}
+
+fn scroll(state: &mut State, amount: f32) -> Option<Action> {
+    let frames = state.frames();
+    state.view.scroll(amount, frames);
+    None
+}
+
+/// Zooms around the cursor.
+fn zoom(state: &mut State, factor: f32) -> Option<Action> {
+    let frames = state.frames();
+    state.view.zoom(factor, state.cursor, frames);
+    None
+}
+
+fn apply_motion(state: &mut State, motion: Motion, count: usize) -> Option<Action> {
+    let operator = state.editor.operator.take();
+    let operator_count = operator.map_or(1, |(_, count)| count);
+    let target = motion.target(state, count.saturating_mul(operator_count))?;
+    let range = state.cursor.min(target)..state.cursor.max(target);
+    let mask = vec![true; state.channels()];
+    match operator {
+        Some((Operator::Delete, _)) => delete(state, range, &mask),
+        Some((Operator::Yank, _)) => yank(state, range, &mask),
+        None => {
+            state.set_cursor(target);
+            if state.editor.mode == Mode::Visual {
+                let anchor = state.editor.anchor;
+                state.select(anchor.min(state.cursor)..anchor.max(state.cursor));
+                return state.looping.then_some(Action::LoopChanged);
+            }
+            None
+        },
+    }
+}
+
+/// Replaces the current file with an edit of it, `removed` frames from `start` having been
+/// replaced with `inserted` frames.
+pub(crate) fn apply_edit(state: &mut State, file: AudioFile, start: usize, removed: usize, inserted: usize) {
+    if let Some(before) = &state.current_file {
+        state.history.record(Delta::new(before, &file, start, removed, inserted));
+    }
+    state.peaks.update(&file.samples, start, removed, inserted);
+    state.current_file = Some(Arc::new(file));
+    state.modified = true;
+}
+
+/// Moves through the history `count` times with `step`, undoing and redoing edits on the way,
+/// or says `message` when there's nowhere to go.
+fn travel(state: &mut State, count: usize, step: fn(&mut History) -> Vec<(Delta, bool)>, message: &str) -> Option<Action> {
+    let mut file = AudioFile::clone(state.current_file.as_ref()?);
+    let mut cursor = None;
+    // Frames changed by all the steps together, as a start, an end before them and an end after
+    // them, so that the peaks are updated once.
+    let mut changed: Option<(usize, usize, usize)> = None;
+    for _ in 0..count {
+        let steps = step(&mut state.history);
+        if steps.is_empty() {
+            break;
+        }
+        for (delta, forward) in steps {
+            file = delta.apply(&file, forward);
+            let (removed, inserted) = delta.lengths(forward);
+            let (start, old_end, new_end) = changed.unwrap_or((delta.start, delta.start, delta.start));
+            let end = new_end.max(delta.start + removed);
+            changed = Some((start.min(delta.start), old_end + end - new_end, end - removed + inserted));
+            cursor = Some(delta.start);
+        }
+    }
+    let (Some(cursor), Some((start, old_end, new_end))) = (cursor, changed) else {
+        state.message = Some(message.to_string());
+        return None;
+    };
+
+    state.peaks.update(&file.samples, start, old_end - start, new_end - start);
+    state.current_file = Some(Arc::new(file));
+    state.modified = !state.history.is_saved();
+    state.set_cursor(cursor);
+    state.clear_selection();
+    Some(Action::FileEdited)
+}
+
+/// Frames and channels commands apply to, the selection or else the whole file.
+pub(crate) fn selected(state: &State) -> (Range<usize>, Vec<bool>) {
+    match &state.selection {
+        Some(selection) => (selection.range(), selection.channels.clone()),
+        None => (0..state.frames(), vec![true; state.channels()]),
+    }
+}
+
+fn selected_channels(mask: &[bool]) -> Vec<usize> {
+    (0..mask.len()).filter(|&c| mask[c]).collect()
+}
+
+/// Deletes frames `range` of the channels set in `mask` into the register. Channels not all
+/// being deleted from have to keep their length, so the frames are silenced instead.
+fn delete(state: &mut State, range: Range<usize>, mask: &[bool]) -> Option<Action> {
+    let file = state.current_file.clone()?;
+    let channels = selected_channels(mask);
+    if range.is_empty() || channels.is_empty() {
+        return None;
+    }
+
+    state.editor.register = Some(file.samples.slice(range.clone(), &channels));
+    let inserted = if channels.len() == file.channels as usize { 0 } else { range.len() };
+    let samples = file.samples.splice(range.clone(), &SampleStore::silence(inserted, channels.len()), mask);
+    apply_edit(state, file.edited(samples, range.start, range.len(), inserted), range.start, range.len(), inserted);
+    state.set_cursor(range.start);
+    state.clear_selection();
+    Some(Action::FileEdited)
+}
+
+fn yank(state: &mut State, range: Range<usize>, mask: &[bool]) -> Option<Action> {
+    let file = state.current_file.as_ref()?;
+    if !range.is_empty() {
+        state.editor.register = Some(file.samples.slice(range.clone(), &selected_channels(mask)));
+    }
+    state.set_cursor(range.start);
+    None
+}
+
+fn delete_selection(state: &mut State) -> Option<Action> {
+    state.editor.mode = Mode::Normal;
+    let selection = state.selection.clone()?;
+    delete(state, selection.range(), &selection.channels)
+}
+
+fn yank_selection(state: &mut State) -> Option<Action> {
+    state.editor.mode = Mode::Normal;
+    let selection = state.selection.clone()?;
+    yank(state, selection.range(), &selection.channels)
+}
+
+/// Inserts the register `count` times at the cursor, leaving the cursor after it when `after`.
+/// A register with fewer channels than the file is repeated across them.
+fn put(state: &mut State, count: usize, after: bool) -> Option<Action> {
+    let file = state.current_file.clone()?;
+    let register = state.editor.register.clone().filter(|r| r.channels() > 0 && r.frames() > 0)?;
+    let channels = file.channels as usize;
+    let order: Vec<usize> = (0..channels).map(|c| c % register.channels()).collect();
+    let clip = register.slice(0..register.frames(), &order);
+
+    let at = state.cursor;
+    let mut samples = file.samples.clone();
+    for _ in 0..count {
+        samples = samples.splice(at..at, &clip, &vec![true; channels]);
+    }
+    let inserted = clip.frames() * count;
+    apply_edit(state, file.edited(samples, at, 0, inserted), at, 0, inserted);
+    if after {
+        state.set_cursor(at + inserted);
+    }
+    Some(Action::FileEdited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peaks::{PeakPyramid, Peaks};
+
+    fn type_keys(state: &mut State, keys: &str) -> Vec<Action> {
+        keys.chars().filter_map(|c| handle_key(state, Key::Char(c))).collect()
+    }
+
+    #[test]
+    fn test_operator_motion() {
+        let samples = SampleStore::from_planar((0..2000).map(|i| i as f32).collect(), 2);
+        let peaks = PeakPyramid::from_peaks(Peaks { channels: Peaks::scan(&samples, 0..1000) });
+        let file = AudioFile::from_store(samples, 44100);
+        let mut state = State { current_file: Some(Arc::new(file)), peaks, ..Default::default() };
+
+        // A step is 10 frames of the 1000 in view
+        type_keys(&mut state, "3l");
+        assert_eq!(state.cursor, 30);
+        type_keys(&mut state, "2d5l");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 900);
+        assert_eq!(state.current_file.as_ref().unwrap().samples.get(1, 30), 1130.0);
+        assert_eq!(state.editor.pending, "");
+
+        // Put the deleted frames back where they were
+        type_keys(&mut state, "P");
+        assert_eq!(state.current_file.as_ref().unwrap().samples.channel(0), (0..1000).map(|i| i as f32).collect::<Vec<_>>());
+
+        // Select to the end in visual mode, then yank and put it at the start
+        type_keys(&mut state, "v$y");
+        assert_eq!(state.editor.mode, Mode::Normal);
+        assert_eq!(state.cursor, 30);
+        type_keys(&mut state, "0p");
+        let file = state.current_file.as_ref().unwrap();
+        assert_eq!(file.frames(), 1970);
+        assert_eq!((file.samples.get(0, 0), file.samples.get(0, 970)), (30.0, 0.0));
+        assert_eq!(state.cursor, 970);
+        assert_eq!(state.peaks.base().len(), 1970usize.div_ceil(crate::peaks::PEAK_FRAMES));
+
+        // Two seconds at 44100 Hz is past the end, which is as far as it goes
+        type_keys(&mut state, "0d2)");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 0);
+
+        // Undo the delete and a put, redo the put, then go back in time to the first delete
+        type_keys(&mut state, "2u");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 1000);
+        assert_eq!(state.peaks.base().len(), 1000usize.div_ceil(crate::peaks::PEAK_FRAMES));
+        handle_key(&mut state, Key::Ctrl('r'));
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 1970);
+        type_keys(&mut state, "2g-");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 900);
+        type_keys(&mut state, "9g+");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 0);
+        type_keys(&mut state, "4u");
+        assert_eq!(state.current_file.as_ref().unwrap().frames(), 1000);
+        type_keys(&mut state, "u");
+        assert_eq!(state.message.as_deref(), Some("Already at oldest change"));
+        assert!(!state.modified);
+
+        // Keys that aren't motions drop a prefix or operator instead of running on their own
+        let frames = state.current_file.as_ref().unwrap().frames();
+        state.select(0..100);
+        assert!(state.selection.is_some());
+        for keys in &["]x", "[x", "gx", "gp", "]p", "dx", "dp", "yx", "dd"] {
+            type_keys(&mut state, keys);
+            assert_eq!(state.current_file.as_ref().unwrap().frames(), frames, "{}", keys);
+            assert_eq!(state.editor.pending, "", "{}", keys);
+        }
+        state.message = None;
+        type_keys(&mut state, "gu");
+        assert_eq!(state.message, None);
+        state.selection = None;
+
+        type_keys(&mut state, "4g+");
+
+        type_keys(&mut state, ":gain");
+        assert_eq!(handle_key(&mut state, Key::Enter), Some(Action::Command("gain".to_string())));
+        assert_eq!(state.editor.mode, Mode::Normal);
+
+        // Tab completes as far as it can, then goes through what's left
+        type_keys(&mut state, ":no");
+        handle_key(&mut state, Key::Tab);
+        assert_eq!(state.editor.command_line, "normalize");
+        type_keys(&mut state, " -1");
+        handle_key(&mut state, Key::Enter);
+        type_keys(&mut state, ":w");
+        handle_key(&mut state, Key::Tab);
+        handle_key(&mut state, Key::Tab);
+        assert_eq!(state.editor.command_line, "wq");
+
+        // Up goes back through the history, down comes back to what was typed
+        handle_key(&mut state, Key::Up);
+        handle_key(&mut state, Key::Up);
+        assert_eq!(state.editor.command_line, "gain");
+        handle_key(&mut state, Key::Down);
+        assert_eq!(state.editor.command_line, "normalize -1");
+        handle_key(&mut state, Key::Down);
+        assert_eq!(state.editor.command_line, "wq");
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::samples::SampleStore;
+use crate::state::{AudioFile, Marker};
+
+/// Edits kept before the oldest ones are forgotten.
+const MAX_EDITS: usize = 1000;
+/// Bytes the history can take before the oldest edits are forgotten. Edits refer to the samples
+/// they replaced rather than copy them, so this is mostly their pieces and markers.
+const MAX_BYTES: usize = 64 << 20;
+
+/// An edit and what it replaced, enough to make it or take it back.
+#[derive(Clone)]
+pub struct Delta {
+    pub start: usize,
+    /// Frames of every channel the edit replaced, then what it replaced them with.
+    removed: SampleStore,
+    inserted: SampleStore,
+    /// Markers and loop points before the edit, then after it.
+    markers_before: Vec<Marker>,
+    markers_after: Vec<Marker>,
+    loop_before: Option<(usize, usize)>,
+    loop_after: Option<(usize, usize)>,
+}
+
+impl Delta {
+    /// What changed from `before` to `after`, `removed` frames from `start` having been replaced
+    /// with `inserted` frames.
+    pub fn new(before: &AudioFile, after: &AudioFile, start: usize, removed: usize, inserted: usize) -> Self {
+        let channels: Vec<usize> = (0..before.channels as usize).collect();
+        Self {
+            start,
+            removed: before.samples.slice(start..start + removed, &channels),
+            inserted: after.samples.slice(start..start + inserted, &channels),
+            markers_before: before.markers.clone(),
+            markers_after: after.markers.clone(),
+            loop_before: before.loop_points,
+            loop_after: after.loop_points,
+        }
+    }
+
+    /// Frames replaced, then frames they were replaced with, when applied `forward`.
+    pub fn lengths(&self, forward: bool) -> (usize, usize) {
+        if forward {
+            (self.removed.frames(), self.inserted.frames())
+        } else {
+            (self.inserted.frames(), self.removed.frames())
+        }
+    }
+
+    /// Makes the edit again on `file`, or takes it back when not `forward`.
+    pub fn apply(&self, file: &AudioFile, forward: bool) -> AudioFile {
+        let (from, to) = if forward { (&self.removed, &self.inserted) } else { (&self.inserted, &self.removed) };
+        let samples = file.samples.splice(self.start..self.start + from.frames(), to, &vec![true; file.channels as usize]);
+        let (markers, loop_points) = if forward {
+            (&self.markers_after, self.loop_after)
+        } else {
+            (&self.markers_before, self.loop_before)
+        };
+        AudioFile { samples, markers: markers.clone(), loop_points, ..file.clone() }
+    }
+
+    fn size(&self) -> usize {
+        let markers = self.markers_before.len() + self.markers_after.len();
+        mem::size_of::<Self>() + self.removed.overhead() + self.inserted.overhead() + markers * mem::size_of::<Marker>()
+    }
+}
+
+struct Node {
+    parent: Option<usize>,
+    /// Edit from the parent's state to this one, the root has none.
+    delta: Option<Delta>,
+    /// Child redo goes to, the last one made or undone.
+    redo: Option<usize>,
+}
+
+/// Every state the file went through as a tree, like vim's, so that undoing then editing
+/// doesn't lose what was undone. States are numbered in the order they were made.
+pub struct History {
+    nodes: BTreeMap<usize, Node>,
+    current: usize,
+    /// State last saved, if it's still in the history.
+    saved: Option<usize>,
+    next: usize,
+    bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(0, Node { parent: None, delta: None, redo: None });
+        Self { nodes, current: 0, saved: Some(0), next: 1, bytes: 0 }
+    }
+}
+
+impl History {
+    /// Adds an edit made from the current state, which it becomes.
+    pub fn record(&mut self, delta: Delta) {
+        let id = self.next;
+        self.next += 1;
+        self.bytes += delta.size();
+        self.nodes.insert(id, Node { parent: Some(self.current), delta: Some(delta), redo: None });
+        self.node(self.current).redo = Some(id);
+        self.current = id;
+
+        while (self.nodes.len() > MAX_EDITS + 1 || self.bytes > MAX_BYTES) && self.forget_oldest() {}
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.current)
+    }
+
+    fn node(&mut self, id: usize) -> &mut Node {
+        self.nodes.get_mut(&id).unwrap()
+    }
+
+    fn parent(&self, id: usize) -> Option<usize> {
+        self.nodes[&id].parent
+    }
+
+    /// Makes the child of the root leading to the current state the new root, dropping the
+    /// root's other branches. Nothing goes when the root is the current state.
+    fn forget_oldest(&mut self) -> bool {
+        let root = *self.nodes.keys().next().unwrap();
+        let mut kept = self.current;
+        while self.parent(kept) != Some(root) {
+            match self.parent(kept) {
+                Some(parent) => kept = parent,
+                None => return false,
+            }
+        }
+
+        // Nodes are made after their parents, so one pass in order finds every descendant
+        let mut forgotten = vec![root];
+        for (&id, node) in self.nodes.range(root + 1..) {
+            if id != kept && node.parent.is_some_and(|parent| forgotten.contains(&parent)) {
+                forgotten.push(id);
+            }
+        }
+        for id in forgotten {
+            if let Some(delta) = self.nodes.remove(&id).and_then(|node| node.delta) {
+                self.bytes -= delta.size();
+            }
+            if self.saved == Some(id) {
+                self.saved = None;
+            }
+        }
+
+        let node = self.node(kept);
+        node.parent = None;
+        if let Some(delta) = node.delta.take() {
+            self.bytes -= delta.size();
+        }
+        true
+    }
+
+    /// Edits to apply, and in which direction, to go from the current state to `target`, which
+    /// becomes the current one.
+    fn travel(&mut self, target: usize) -> Vec<(Delta, bool)> {
+        let ancestors = |mut id: usize| {
+            let mut ancestors = vec![id];
+            while let Some(parent) = self.parent(id) {
+                ancestors.push(parent);
+                id = parent;
+            }
+            ancestors
+        };
+        let up = ancestors(self.current);
+        let down = ancestors(target);
+        let common = *up.iter().find(|id| down.contains(id)).unwrap();
+
+        let mut steps = vec![];
+        for &id in up.iter().take_while(|&&id| id != common) {
+            let parent = self.parent(id).unwrap();
+            self.node(parent).redo = Some(id);
+            steps.push((self.nodes[&id].delta.clone().unwrap(), false));
+        }
+        for &&id in down.iter().take_while(|&&id| id != common).collect::<Vec<_>>().iter().rev() {
+            let parent = self.parent(id).unwrap();
+            self.node(parent).redo = Some(id);
+            steps.push((self.nodes[&id].delta.clone().unwrap(), true));
+        }
+        self.current = target;
+        steps
+    }
+
+    /// Takes the last edit back.
+    pub fn undo(&mut self) -> Vec<(Delta, bool)> {
+        match self.parent(self.current) {
+            Some(parent) => self.travel(parent),
+            None => vec![],
+        }
+    }
+
+    /// Makes the last edit undone again.
+    pub fn redo(&mut self) -> Vec<(Delta, bool)> {
+        match self.nodes[&self.current].redo {
+            Some(child) => self.travel(child),
+            None => vec![],
+        }
+    }
+
+    /// Goes back to the state made just before the current one, whatever branch it's on.
+    pub fn earlier(&mut self) -> Vec<(Delta, bool)> {
+        match self.nodes.range(..self.current).next_back() {
+            Some((&id, _)) => self.travel(id),
+            None => vec![],
+        }
+    }
+
+    /// Goes to the state made just after the current one, whatever branch it's on.
+    pub fn later(&mut self) -> Vec<(Delta, bool)> {
+        match self.nodes.range(self.current + 1..).next() {
+            Some((&id, _)) => self.travel(id),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(samples: &[f32]) -> AudioFile {
+        AudioFile::from_store(SampleStore::from_planar(samples.to_vec(), 1), 44100)
+    }
+
+    fn replay(file: &AudioFile, steps: Vec<(Delta, bool)>) -> AudioFile {
+        steps.iter().fold(file.clone(), |file, (delta, forward)| delta.apply(&file, *forward))
+    }
+
+    #[test]
+    fn test_undo_tree() {
+        let mut history = History::default();
+        let original = file(&[0.0, 1.0, 2.0, 3.0]);
+
+        // Delete a frame, undo, then put another one in instead
+        let deleted = AudioFile { samples: original.samples.splice(1..2, &SampleStore::silence(0, 1), &[true]), ..original.clone() };
+        history.record(Delta::new(&original, &deleted, 1, 1, 0));
+        let undone = replay(&deleted, history.undo());
+        assert_eq!(undone.samples.channel(0), original.samples.channel(0));
+
+        let inserted = AudioFile { samples: undone.samples.splice(0..0, &SampleStore::from_planar(vec![9.0], 1), &[true]), ..undone.clone() };
+        history.record(Delta::new(&undone, &inserted, 0, 0, 1));
+        assert!(!history.is_saved());
+
+        // Going back in time reaches the deletion on the other branch
+        let earlier = replay(&inserted, history.earlier());
+        assert_eq!(earlier.samples.channel(0), [0.0, 2.0, 3.0]);
+        let earlier = replay(&earlier, history.earlier());
+        assert_eq!(earlier.samples.channel(0), [0.0, 1.0, 2.0, 3.0]);
+        assert!(history.is_saved());
+        assert!(history.earlier().is_empty());
+        let later = replay(&earlier, history.later());
+        let later = replay(&later, history.later());
+        assert_eq!(later.samples.channel(0), [9.0, 0.0, 1.0, 2.0, 3.0]);
+
+        // Redo goes down the branch last undone
+        let undone = replay(&later, history.undo());
+        let redone = replay(&undone, history.redo());
+        assert_eq!(redone.samples.channel(0), [9.0, 0.0, 1.0, 2.0, 3.0]);
+        assert!(history.redo().is_empty());
+
+        // The oldest edits go once there are too many
+        for _ in 0..MAX_EDITS + 10 {
+            history.record(Delta::new(&redone, &redone, 0, 0, 0));
+        }
+        assert_eq!(history.nodes.len(), MAX_EDITS + 1);
+        assert_eq!(history.saved, None);
+        assert_eq!(history.bytes, history.nodes.values().filter_map(|n| n.delta.as_ref()).map(Delta::size).sum::<usize>());
+    }
+}
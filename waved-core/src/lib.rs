@@ -1,2 +1,8 @@
 pub mod state;
+pub mod samples;
+pub mod peaks;
+pub mod editor;
+pub mod command;
+pub mod history;
+pub mod motion;
 pub mod log;
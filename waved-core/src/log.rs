@@ -1,5 +1,6 @@
 use std::error::Error;
 
+#[derive(Default)]
 pub struct Logger {
     messages: Vec<String>,
 }
@@ -10,6 +11,10 @@ impl Logger {
     }
 
     pub fn log<E: Error>(&mut self, err: E) {
-        self.messages.push(format!("{:?}", err));
+        self.messages.push(format!("{}", err));
+    }
+
+    pub fn last(&self) -> Option<&str> {
+        self.messages.last().map(String::as_str)
     }
 }
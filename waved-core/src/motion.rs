@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use crate::peaks::{PeakPyramid, PEAK_FRAMES};
+use crate::samples::SampleStore;
+use crate::state::Marker;
+
+/// Frames read at a time when searching the samples.
+const SEARCH_FRAMES: usize = 4096;
+
+/// Level under which a run of peaks counts as silence, -60 dBFS.
+pub const SILENCE_THRESHOLD: f32 = 0.001;
+/// How much louder than the peaks just before a peak has to be to start a transient, +6 dB.
+const ONSET_RATIO: f32 = 2.0;
+/// Level under which nothing counts as a transient, -50 dBFS.
+const ONSET_FLOOR: f32 = 0.003;
+/// Peaks averaged to get the level a transient stands out of.
+const ONSET_HISTORY: usize = 4;
+
+/// Average of all channels of frames `start..start + length`, past the end is silent.
+fn mix(samples: &SampleStore, start: usize, length: usize) -> Vec<f32> {
+    let mut mix = vec![0.0; length];
+    let mut channel = vec![0.0; length];
+    for c in 0..samples.channels() {
+        samples.read(c, start, &mut channel);
+        mix.iter_mut().zip(&channel).for_each(|(m, s)| *m += s);
+    }
+    let scale = 1.0 / samples.channels().max(1) as f32;
+    mix.iter_mut().for_each(|m| *m *= scale);
+    mix
+}
+
+/// Next frame after `from`, or last one before it, where the mix of all channels changes sign.
+pub fn zero_crossing(samples: &SampleStore, from: usize, forward: bool) -> Option<usize> {
+    let frames = samples.frames();
+    let crosses = |a: f32, b: f32| (a < 0.0) != (b < 0.0);
+    if forward {
+        // Blocks overlap by a frame, so that crossings between them are seen
+        let mut start = from;
+        while start + 1 < frames {
+            let block = mix(samples, start, SEARCH_FRAMES.min(frames - start));
+            if let Some(i) = block.windows(2).position(|w| crosses(w[0], w[1])) {
+                return Some(start + i + 1);
+            }
+            start += block.len() - 1;
+        }
+    } else {
+        let mut end = from.min(frames);
+        while end > 1 {
+            let start = end.saturating_sub(SEARCH_FRAMES);
+            let block = mix(samples, start, end - start);
+            if let Some(i) = block.windows(2).rposition(|w| crosses(w[0], w[1])) {
+                return Some(start + i + 1);
+            }
+            end = start + 1;
+        }
+    }
+    None
+}
+
+/// Level of peak `i`, the RMS of all channels.
+fn level(peaks: &PeakPyramid, i: usize) -> f32 {
+    let channels = &peaks.base().channels;
+    (channels.iter().map(|c| c[i].rms * c[i].rms).sum::<f32>() / channels.len() as f32).sqrt()
+}
+
+/// Whether peak `i` is much louder than the peaks before it.
+fn is_onset(peaks: &PeakPyramid, i: usize) -> bool {
+    if i == 0 {
+        return false;
+    }
+    let history = i.saturating_sub(ONSET_HISTORY)..i;
+    let before = history.clone().map(|j| level(peaks, j)).sum::<f32>() / history.len() as f32;
+    let level = level(peaks, i);
+    level > ONSET_FLOOR && level > before * ONSET_RATIO
+}
+
+/// Frame where the transient starting in peak `i` does, the first sample within half of the
+/// loudest of the peak.
+fn onset_frame(samples: &SampleStore, i: usize) -> usize {
+    let block = mix(samples, i * PEAK_FRAMES, PEAK_FRAMES);
+    let loudest = block.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    i * PEAK_FRAMES + block.iter().position(|s| s.abs() >= loudest * 0.5).unwrap_or(0)
+}
+
+/// Runs of frames quieter than `SILENCE_THRESHOLD` in every channel, at least `min_frames` long.
+pub fn silences(peaks: &PeakPyramid, frames: usize, min_frames: usize) -> Vec<Range<usize>> {
+    let channels = &peaks.base().channels;
+    let silent = |i: usize| channels.iter().all(|c| c[i].max < SILENCE_THRESHOLD && c[i].min > -SILENCE_THRESHOLD);
+
+    let mut silences = vec![];
+    let mut start = None;
+    for i in 0..=peaks.base().len() {
+        match (start, i < peaks.base().len() && silent(i)) {
+            (None, true) => start = Some(i),
+            (Some(first), false) => {
+                let range = first * PEAK_FRAMES..(i * PEAK_FRAMES).min(frames);
+                if range.len() >= min_frames {
+                    silences.push(range);
+                }
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    silences
+}
+
+/// `count`th of sorted `positions` after `from`, or before it going backward. Stops at the last
+/// one there is when there are fewer.
+fn nearest(positions: Vec<usize>, from: usize, forward: bool, count: usize) -> Option<usize> {
+    if forward {
+        positions.into_iter().filter(|&p| p > from).take(count).last()
+    } else {
+        positions.into_iter().rev().filter(|&p| p < from).take(count).last()
+    }
+}
+
+/// `count`th transient after `from`, or before it going backward, found by peaks much louder
+/// than the ones before them. Stops at the last one there is when there are fewer.
+pub fn transient(samples: &SampleStore, peaks: &PeakPyramid, from: usize, forward: bool, count: usize) -> Option<usize> {
+    let first = (from / PEAK_FRAMES).min(peaks.base().len().checked_sub(1)?);
+    // A rise over several peaks is a single transient
+    let onset = |i: usize| (is_onset(peaks, i) && !is_onset(peaks, i - 1)).then(|| onset_frame(samples, i));
+    if forward {
+        (first..peaks.base().len()).filter_map(onset).filter(|&p| p > from).take(count).last()
+    } else {
+        (0..=first).rev().filter_map(onset).filter(|&p| p < from).take(count).last()
+    }
+}
+
+/// `count`th start or end of a silence after `from`, or before it going backward.
+pub fn silence_boundary(peaks: &PeakPyramid, frames: usize, min_frames: usize, from: usize, forward: bool, count: usize) -> Option<usize> {
+    let boundaries = silences(peaks, frames, min_frames).into_iter().flat_map(|s| [s.start, s.end]).collect();
+    nearest(boundaries, from, forward, count)
+}
+
+/// `count`th marker after `from`, or before it going backward.
+pub fn marker(markers: &[Marker], from: usize, forward: bool, count: usize) -> Option<usize> {
+    let mut positions: Vec<usize> = markers.iter().map(|m| m.position).collect();
+    positions.sort_unstable();
+    nearest(positions, from, forward, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peaks::Peaks;
+
+    #[test]
+    fn test_motions() {
+        // Silence, then a sine starting at a hit
+        let hit = PEAK_FRAMES * 40 + 100;
+        let frames = PEAK_FRAMES * 80;
+        let samples: Vec<f32> = (0..frames)
+            .map(|i| if i < hit { 0.0 } else { (i - hit) as f32 * 0.1 + 0.05 })
+            .map(|phase| if phase == 0.0 { 0.0 } else { phase.sin() * 0.8 })
+            .collect();
+        let store = SampleStore::from_planar(samples.clone(), 1);
+        let peaks = PeakPyramid::from_peaks(Peaks { channels: Peaks::scan(&store, 0..frames) });
+
+        let crossing = zero_crossing(&store, hit, true).unwrap();
+        assert!(samples[crossing - 1] >= 0.0 && samples[crossing] < 0.0);
+        let next = zero_crossing(&store, crossing, true).unwrap();
+        assert!(samples[next - 1] < 0.0 && samples[next] >= 0.0);
+        assert_eq!(zero_crossing(&store, next, false), Some(crossing));
+        // Silence isn't negative, there's nothing to cross before the hit
+        assert_eq!(zero_crossing(&store, crossing, false), None);
+
+        let onset = transient(&store, &peaks, 0, true, 1).unwrap();
+        assert!((hit..hit + 20).contains(&onset));
+        assert_eq!(transient(&store, &peaks, onset, true, 1), None);
+        assert_eq!(transient(&store, &peaks, frames, false, 3), Some(onset));
+
+        let silence = silences(&peaks, frames, PEAK_FRAMES * 10);
+        assert_eq!((silence.len(), silence[0].clone()), (1, 0..PEAK_FRAMES * 40));
+        assert_eq!(silence_boundary(&peaks, frames, PEAK_FRAMES * 10, 0, true, 1), Some(PEAK_FRAMES * 40));
+
+        let markers = [Marker { position: 500, name: "b".into() }, Marker { position: 20, name: "a".into() }];
+        assert_eq!(marker(&markers, 20, true, 1), Some(500));
+        assert_eq!(marker(&markers, 0, true, 5), Some(500));
+        assert_eq!(marker(&markers, 500, false, 1), Some(20));
+    }
+}
@@ -0,0 +1,373 @@
+use std::ops::Range;
+
+use crate::samples::SampleStore;
+
+/// Frames summarized by each peak.
+pub const PEAK_FRAMES: usize = 256;
+
+/// Peaks of a level merged into each peak of the level above.
+const LEVEL_FACTOR: usize = 4;
+
+/// Summary of a run of samples, enough to draw them without looking at every one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Peak {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+impl Peak {
+    /// Combines the peaks of consecutive runs of the same length.
+    pub fn merge(peaks: &[Peak]) -> Self {
+        if peaks.is_empty() {
+            return Self::default();
+        }
+        let sum_of_squares: f32 = peaks.iter().map(|p| p.rms * p.rms).sum();
+        Self {
+            min: peaks.iter().map(|p| p.min).fold(f32::INFINITY, f32::min),
+            max: peaks.iter().map(|p| p.max).fold(f32::NEG_INFINITY, f32::max),
+            rms: (sum_of_squares / peaks.len() as f32).sqrt(),
+        }
+    }
+
+    /// Computes the peak of frames `range` of `channel` straight from the samples, for ranges too
+    /// short to be drawn from peaks.
+    pub fn scan(samples: &SampleStore, channel: usize, range: Range<usize>) -> Option<Self> {
+        let mut builder = PeakBuilder::new();
+        samples.visit(channel, range, |block| block.iter().for_each(|&s| builder.add(s)));
+        (builder.count > 0).then(|| builder.build())
+    }
+}
+
+/// Accumulates samples into a peak.
+struct PeakBuilder {
+    min: f32,
+    max: f32,
+    sum_of_squares: f32,
+    count: usize,
+}
+
+impl PeakBuilder {
+    fn new() -> Self {
+        Self { min: f32::INFINITY, max: f32::NEG_INFINITY, sum_of_squares: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, s: f32) {
+        self.min = self.min.min(s);
+        self.max = self.max.max(s);
+        self.sum_of_squares += s * s;
+        self.count += 1;
+    }
+
+    fn build(&mut self) -> Peak {
+        let peak = Peak { min: self.min, max: self.max, rms: (self.sum_of_squares / self.count as f32).sqrt() };
+        *self = Self::new();
+        peak
+    }
+}
+
+/// Peaks of every channel of a file, one per `PEAK_FRAMES` frames.
+#[derive(Clone, Debug, Default)]
+pub struct Peaks {
+    pub channels: Vec<Vec<Peak>>,
+}
+
+impl Peaks {
+    pub fn new(channels: usize) -> Self {
+        Self { channels: vec![vec![]; channels] }
+    }
+
+    /// Computes the peaks of frames `range` of every channel. The range should start on a peak
+    /// boundary, the last peak only covers what's left of it.
+    pub fn scan(samples: &SampleStore, range: Range<usize>) -> Vec<Vec<Peak>> {
+        (0..samples.channels())
+            .map(|c| {
+                let mut peaks = Vec::with_capacity(range.len().div_ceil(PEAK_FRAMES));
+                let mut builder = PeakBuilder::new();
+                samples.visit(c, range.clone(), |block| {
+                    for &s in block {
+                        builder.add(s);
+                        if builder.count == PEAK_FRAMES {
+                            peaks.push(builder.build());
+                        }
+                    }
+                });
+                if builder.count > 0 {
+                    peaks.push(builder.build());
+                }
+                peaks
+            })
+            .collect()
+    }
+
+    /// Adds peaks following the ones already there, as computed by `scan`.
+    pub fn append(&mut self, peaks: Vec<Vec<Peak>>) {
+        self.channels.resize(peaks.len(), vec![]);
+        for (channel, peaks) in self.channels.iter_mut().zip(peaks) {
+            channel.extend(peaks);
+        }
+    }
+
+    /// Number of peaks in each channel.
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Peaks at every power of `LEVEL_FACTOR` times `PEAK_FRAMES`, so that drawing any part of a file
+/// at any zoom only merges a handful of them per pixel.
+#[derive(Clone, Debug)]
+pub struct PeakPyramid {
+    /// From the finest level up, each one summarizing `LEVEL_FACTOR` peaks of the one below.
+    levels: Vec<Peaks>,
+    /// First peak of the base from which peaks were only approximated after an edit, until
+    /// `refine` rescans them.
+    stale: Option<usize>,
+}
+
+impl Default for PeakPyramid {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl PeakPyramid {
+    pub fn new(channels: usize) -> Self {
+        Self { levels: vec![Peaks::new(channels)], stale: None }
+    }
+
+    pub fn from_peaks(peaks: Peaks) -> Self {
+        let mut pyramid = Self { levels: vec![peaks], stale: None };
+        pyramid.build_from(0);
+        pyramid
+    }
+
+    /// The finest level, one peak per `PEAK_FRAMES` frames.
+    pub fn base(&self) -> &Peaks {
+        &self.levels[0]
+    }
+
+    /// Whether every peak was computed from the samples, none of them approximated.
+    pub fn is_exact(&self) -> bool {
+        self.stale.is_none()
+    }
+
+    fn frames_per_peak(level: usize) -> usize {
+        PEAK_FRAMES * LEVEL_FACTOR.pow(level as u32)
+    }
+
+    /// Adds peaks following the ones already there, as computed by `Peaks::scan`.
+    pub fn append(&mut self, peaks: Vec<Vec<Peak>>) {
+        // The last peak may have been partial and is merged again along with the new ones
+        let first = self.levels[0].len().saturating_sub(1);
+        self.levels[0].append(peaks);
+        self.build_from(first);
+    }
+
+    /// Updates the peaks after `removed` frames from `start` were replaced with `inserted` frames
+    /// of `samples`. Only the edited frames are scanned. The frames after them moved, their peaks
+    /// move along when they moved by whole peaks, otherwise they are approximated from the peaks
+    /// they now straddle until `refine` gets to them.
+    pub fn update(&mut self, samples: &SampleStore, start: usize, removed: usize, inserted: usize) {
+        let frames = samples.frames();
+        let count = frames.div_ceil(PEAK_FRAMES);
+        let first = start / PEAK_FRAMES;
+        if self.levels[0].channels.len() != samples.channels() {
+            self.levels[0] = Peaks { channels: Peaks::scan(samples, 0..frames) };
+            self.stale = None;
+            self.build_from(0);
+            return;
+        }
+        if removed == inserted {
+            let last = (start + inserted).div_ceil(PEAK_FRAMES);
+            self.rescan(samples, first, last);
+            return;
+        }
+
+        // Old peaks from `old_tail` on only cover frames that moved, by `shift` frames
+        let old_tail = (start + removed).div_ceil(PEAK_FRAMES);
+        let shift = inserted as isize - removed as isize;
+        let aligned = shift % PEAK_FRAMES as isize == 0;
+        let new_tail = if aligned {
+            (old_tail as isize + shift / PEAK_FRAMES as isize) as usize
+        } else {
+            (start + inserted).div_ceil(PEAK_FRAMES)
+        };
+
+        let scanned = Peaks::scan(samples, first * PEAK_FRAMES..(new_tail * PEAK_FRAMES).min(frames));
+        for (channel, peaks) in self.levels[0].channels.iter_mut().zip(scanned) {
+            let tail: Vec<Peak> = if aligned {
+                channel.get(old_tail..).unwrap_or_default().to_vec()
+            } else {
+                (new_tail..count)
+                    .map(|i| {
+                        let old = (i * PEAK_FRAMES) as isize - shift;
+                        let j = (old / PEAK_FRAMES as isize) as usize;
+                        Peak::merge(&channel[j.min(channel.len())..(j + 2).min(channel.len())])
+                    })
+                    .collect()
+            };
+            channel.truncate(first);
+            channel.extend(peaks);
+            channel.extend(tail);
+        }
+
+        let stale = match self.stale {
+            Some(stale) if stale < first => Some(stale),
+            _ if !aligned => Some(new_tail),
+            Some(stale) if stale >= old_tail => Some(stale - old_tail + new_tail),
+            Some(_) => Some(new_tail),
+            None => None,
+        };
+        self.stale = stale.filter(|&stale| stale < count);
+        self.build_from(first);
+    }
+
+    /// Rescans up to `max_frames` frames of the peaks `update` approximated, returns whether
+    /// some are left.
+    pub fn refine(&mut self, samples: &SampleStore, max_frames: usize) -> bool {
+        if let Some(first) = self.stale {
+            let last = (first + max_frames.div_ceil(PEAK_FRAMES)).min(self.levels[0].len());
+            self.rescan(samples, first, last);
+            self.stale = Some(last).filter(|&last| last < self.levels[0].len());
+        }
+        self.stale.is_some()
+    }
+
+    /// Rescans base peaks `first..last`, which cover the same frames as before.
+    fn rescan(&mut self, samples: &SampleStore, first: usize, last: usize) {
+        let end = (last * PEAK_FRAMES).min(samples.frames());
+        let scanned = Peaks::scan(samples, first * PEAK_FRAMES..end);
+        for (channel, peaks) in self.levels[0].channels.iter_mut().zip(scanned) {
+            let last = (first + peaks.len()).min(channel.len());
+            channel.splice(first..last, peaks);
+        }
+        self.build_range(first, last);
+    }
+
+    /// Rebuilds the levels above the base from its peak `first` onward.
+    fn build_from(&mut self, mut first: usize) {
+        let mut level = 1;
+        while self.levels[level - 1].len() > 1 {
+            first /= LEVEL_FACTOR;
+            if level == self.levels.len() {
+                self.levels.push(Peaks::new(self.levels[0].channels.len()));
+            }
+            let (below, above) = self.levels.split_at_mut(level);
+            for (channel, peaks) in above[0].channels.iter_mut().zip(&below[level - 1].channels) {
+                channel.truncate(first);
+                channel.extend(peaks[first * LEVEL_FACTOR..].chunks(LEVEL_FACTOR).map(Peak::merge));
+            }
+            level += 1;
+        }
+        self.levels.truncate(level);
+    }
+
+    /// Rebuilds the peaks of the levels above the base covering its peaks `first..last`, when
+    /// there are as many base peaks as before.
+    fn build_range(&mut self, mut first: usize, mut last: usize) {
+        for level in 1..self.levels.len() {
+            first /= LEVEL_FACTOR;
+            last = last.div_ceil(LEVEL_FACTOR);
+            let (below, above) = self.levels.split_at_mut(level);
+            for (channel, peaks) in above[0].channels.iter_mut().zip(&below[level - 1].channels) {
+                let end = (last * LEVEL_FACTOR).min(peaks.len());
+                let merged = peaks[first * LEVEL_FACTOR..end].chunks(LEVEL_FACTOR).map(Peak::merge);
+                channel[first..last].iter_mut().zip(merged).for_each(|(peak, merged)| *peak = merged);
+            }
+        }
+    }
+
+    /// Peak of frames `range` of `channel`, taken from the coarsest level that still has a peak
+    /// per `range.len()` frames. Frames with no peak yet have none.
+    pub fn peak(&self, channel: usize, range: Range<usize>) -> Option<Peak> {
+        let level = (0..self.levels.len())
+            .rev()
+            .find(|&level| Self::frames_per_peak(level) <= range.len())
+            .unwrap_or(0);
+        let frames_per_peak = Self::frames_per_peak(level);
+        let peaks = self.levels[level].channels.get(channel)?;
+        let first = range.start / frames_per_peak;
+        let last = range.end.div_ceil(frames_per_peak).max(first + 1).min(peaks.len());
+        (first < last).then(|| Peak::merge(&peaks[first..last]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_in_parts() {
+        let samples: Vec<f32> = (0..PEAK_FRAMES * 3 + 10).map(|i| (i % 7) as f32 / 6.0 - 0.5).collect();
+        let store = SampleStore::from_planar(samples, 1);
+
+        let mut peaks = Peaks::new(1);
+        peaks.append(Peaks::scan(&store, 0..PEAK_FRAMES * 2));
+        peaks.append(Peaks::scan(&store, PEAK_FRAMES * 2..store.frames()));
+        assert_eq!(peaks.len(), 4);
+        assert_eq!(peaks.channels[0][0], Peaks::scan(&store, 0..PEAK_FRAMES)[0][0]);
+        assert_eq!((peaks.channels[0][3].min, peaks.channels[0][3].max), (-0.5, 0.5));
+
+        let merged = Peak::merge(&peaks.channels[0][0..2]);
+        assert_eq!((merged.min, merged.max), (-0.5, 0.5));
+        assert!((merged.rms - peaks.channels[0][0].rms).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_pyramid_update() {
+        let frames = PEAK_FRAMES * 100 + 30;
+        let samples: Vec<f32> = (0..frames).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        let store = SampleStore::from_planar(samples.clone(), 1);
+
+        let mut pyramid = PeakPyramid::new(1);
+        pyramid.append(Peaks::scan(&store, 0..PEAK_FRAMES * 40));
+        pyramid.append(Peaks::scan(&store, PEAK_FRAMES * 40..frames));
+        let whole = pyramid.peak(0, 0..frames).unwrap();
+        assert!((whole.max - 0.5).abs() < 0.01 && (whole.min + 0.5).abs() < 0.01);
+        assert_eq!(pyramid.levels.len(), 5);
+        assert!(pyramid.peak(0, frames + PEAK_FRAMES..frames + PEAK_FRAMES * 2).is_none());
+
+        // A loud click in place, then the same frames removed
+        let mut edited = samples.clone();
+        edited[PEAK_FRAMES * 50 + 3] = 1.0;
+        let store = SampleStore::from_planar(edited.clone(), 1);
+        pyramid.update(&store, PEAK_FRAMES * 50 + 3, 1, 1);
+        assert_eq!(pyramid.peak(0, 0..frames).unwrap().max, 1.0);
+        assert_eq!(pyramid.peak(0, 0..PEAK_FRAMES * 16).unwrap().max, whole.max);
+
+        let assert_rebuilt = |pyramid: &PeakPyramid, store: &SampleStore| {
+            let rebuilt = PeakPyramid::from_peaks(Peaks { channels: Peaks::scan(store, 0..store.frames()) });
+            assert_eq!(pyramid.levels.len(), rebuilt.levels.len());
+            for (level, expected) in pyramid.levels.iter().zip(&rebuilt.levels) {
+                assert_eq!(level.channels, expected.channels);
+            }
+        };
+
+        // Removing whole peaks moves the ones after them as they are
+        edited.drain(PEAK_FRAMES * 50..PEAK_FRAMES * 51);
+        let store = SampleStore::from_planar(edited.clone(), 1);
+        pyramid.update(&store, PEAK_FRAMES * 50, PEAK_FRAMES, 0);
+        assert!(pyramid.is_exact());
+        assert_rebuilt(&pyramid, &store);
+
+        // Removing a single frame leaves the peaks after it approximate, until refined
+        edited.remove(PEAK_FRAMES * 10 + 7);
+        let store = SampleStore::from_planar(edited, 1);
+        pyramid.update(&store, PEAK_FRAMES * 10 + 7, 1, 0);
+        assert_eq!(pyramid.stale, Some(11));
+        assert_eq!(pyramid.base().len(), store.frames().div_ceil(PEAK_FRAMES));
+        assert_eq!(pyramid.peak(0, 0..store.frames()).unwrap().max, whole.max);
+        let mut passes = 0;
+        while pyramid.refine(&store, PEAK_FRAMES * 30) {
+            passes += 1;
+        }
+        assert_eq!(passes, 2);
+        assert!(pyramid.is_exact());
+        assert_rebuilt(&pyramid, &store);
+    }
+}
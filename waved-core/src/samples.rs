@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Frames of a single channel held by a page.
+pub const PAGE_FRAMES: usize = 1 << 16;
+
+/// Pages kept around by a store, 16 MB worth of samples.
+const CACHE_PAGES: usize = 64;
+
+/// Where a store reads its samples from, decoding them on demand.
+pub trait PageSource: Send + Sync {
+    fn frames(&self) -> usize;
+
+    /// Fills `out` with frames `start..start + out.len()` of `channel`, which are all in the source.
+    fn read(&self, channel: usize, start: usize, out: &mut [f32]);
+
+    /// What the source is made of when it's the result of edits.
+    fn pieces(&self) -> Option<&[Vec<Piece>]> {
+        None
+    }
+}
+
+/// Samples already decoded into memory.
+pub struct MemorySource {
+    samples: Vec<f32>,
+    channels: usize,
+    interleaved: bool,
+}
+
+impl MemorySource {
+    pub fn planar(samples: Vec<f32>, channels: usize) -> Self {
+        Self { samples, channels, interleaved: false }
+    }
+
+    /// Keeps the frames interleaved rather than making a planar copy of them.
+    pub fn interleaved(samples: Vec<f32>, channels: usize) -> Self {
+        Self { samples, channels, interleaved: true }
+    }
+}
+
+impl PageSource for MemorySource {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    fn read(&self, channel: usize, start: usize, out: &mut [f32]) {
+        if self.interleaved {
+            let samples = self.samples[start * self.channels + channel..].iter().step_by(self.channels);
+            for (o, s) in out.iter_mut().zip(samples) {
+                *o = *s;
+            }
+        } else {
+            let offset = channel * self.frames() + start;
+            out.copy_from_slice(&self.samples[offset..offset + out.len()]);
+        }
+    }
+}
+
+/// Run of frames of a channel of another store, or of silence.
+#[derive(Clone)]
+pub struct Piece {
+    store: Option<SampleStore>,
+    channel: usize,
+    start: usize,
+    length: usize,
+    /// What the frames are multiplied by.
+    gain: f32,
+}
+
+impl Piece {
+    /// Frames `range` of the piece.
+    fn part(&self, range: Range<usize>) -> Self {
+        Self { start: self.start + range.start, length: range.len(), ..self.clone() }
+    }
+}
+
+/// Frames `range` of a channel made of `pieces`.
+fn cut(pieces: &[Piece], range: Range<usize>) -> Vec<Piece> {
+    let mut cut = vec![];
+    let mut position = 0;
+    for piece in pieces {
+        let start = range.start.max(position);
+        let end = range.end.min(position + piece.length);
+        if start < end {
+            cut.push(piece.part(start - position..end - position));
+        }
+        position += piece.length;
+    }
+    cut
+}
+
+/// Channels pieced together from other stores, which is how edits are made without copying
+/// any samples.
+struct PieceSource {
+    channels: Vec<Vec<Piece>>,
+}
+
+impl PageSource for PieceSource {
+    fn frames(&self) -> usize {
+        self.channels.first().map_or(0, |pieces| pieces.iter().map(|p| p.length).sum())
+    }
+
+    fn read(&self, channel: usize, start: usize, out: &mut [f32]) {
+        let mut written = 0;
+        for piece in cut(&self.channels[channel], start..start + out.len()) {
+            let out = &mut out[written..written + piece.length];
+            match &piece.store {
+                Some(store) => {
+                    store.read(piece.channel, piece.start, out);
+                    if piece.gain != 1.0 {
+                        out.iter_mut().for_each(|s| *s *= piece.gain);
+                    }
+                },
+                None => out.iter_mut().for_each(|s| *s = 0.0),
+            }
+            written += piece.length;
+        }
+    }
+
+    fn pieces(&self) -> Option<&[Vec<Piece>]> {
+        Some(&self.channels)
+    }
+}
+
+struct CachedPage {
+    samples: Arc<[f32]>,
+    /// Clock of the last access.
+    used: u64,
+}
+
+/// Least recently used pages, keyed by channel and page index.
+#[derive(Default)]
+struct PageCache {
+    pages: HashMap<(usize, usize), CachedPage>,
+    clock: u64,
+}
+
+impl PageCache {
+    fn get(&mut self, key: (usize, usize)) -> Option<Arc<[f32]>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.pages.get_mut(&key).map(|page| {
+            page.used = clock;
+            page.samples.clone()
+        })
+    }
+
+    fn insert(&mut self, key: (usize, usize), samples: Arc<[f32]>) {
+        if self.pages.len() >= CACHE_PAGES {
+            let oldest = self.pages.iter().min_by_key(|(_, page)| page.used).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.pages.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.pages.insert(key, CachedPage { samples, used: self.clock });
+    }
+}
+
+/// Planar samples read a page at a time from a source, so that only the parts of a file being
+/// looked at or played have to be decoded. Clones share the source and the cache.
+#[derive(Clone)]
+pub struct SampleStore {
+    source: Arc<dyn PageSource>,
+    channels: usize,
+    frames: usize,
+    cache: Arc<Mutex<PageCache>>,
+}
+
+impl SampleStore {
+    pub fn new(source: Arc<dyn PageSource>, channels: usize) -> Self {
+        Self {
+            frames: source.frames(),
+            source,
+            channels,
+            cache: Default::default(),
+        }
+    }
+
+    pub fn from_planar(samples: Vec<f32>, channels: usize) -> Self {
+        Self::new(Arc::new(MemorySource::planar(samples, channels)), channels)
+    }
+
+    pub fn from_interleaved(samples: Vec<f32>, channels: usize) -> Self {
+        Self::new(Arc::new(MemorySource::interleaved(samples, channels)), channels)
+    }
+
+    /// `frames` frames of silence.
+    pub fn silence(frames: usize, channels: usize) -> Self {
+        let piece = Piece { store: None, channel: 0, start: 0, length: frames, gain: 1.0 };
+        Self::from_pieces(vec![if frames > 0 { vec![piece] } else { vec![] }; channels])
+    }
+
+    fn from_pieces(channels: Vec<Vec<Piece>>) -> Self {
+        let count = channels.len();
+        Self::new(Arc::new(PieceSource { channels }), count)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Rough number of bytes the store takes on top of the samples it refers to.
+    pub fn overhead(&self) -> usize {
+        let pieces: usize = self.source.pieces().map_or(0, |channels| channels.iter().map(Vec::len).sum());
+        mem::size_of::<Self>() + pieces * mem::size_of::<Piece>()
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    fn page(&self, channel: usize, index: usize) -> Arc<[f32]> {
+        let key = (channel, index);
+        if let Some(page) = self.cache.lock().unwrap().get(key) {
+            return page;
+        }
+
+        // Decode without holding the lock, a page costs more than the odd one decoded twice.
+        let start = index * PAGE_FRAMES;
+        let mut page = vec![0.0; PAGE_FRAMES.min(self.frames - start)];
+        self.source.read(channel, start, &mut page);
+        let page: Arc<[f32]> = page.into();
+        self.cache.lock().unwrap().insert(key, page.clone());
+        page
+    }
+
+    /// Calls `f` with consecutive runs of samples of `channel` that together cover `range`.
+    pub fn visit<F: FnMut(&[f32])>(&self, channel: usize, range: Range<usize>, mut f: F) {
+        let mut position = range.start;
+        let end = range.end.min(self.frames);
+        while position < end {
+            let index = position / PAGE_FRAMES;
+            let page = self.page(channel, index);
+            let offset = position - index * PAGE_FRAMES;
+            let length = (end - position).min(page.len() - offset);
+            f(&page[offset..offset + length]);
+            position += length;
+        }
+    }
+
+    /// Copies frames `start..start + out.len()` of `channel`, frames past the end are silent.
+    pub fn read(&self, channel: usize, start: usize, out: &mut [f32]) {
+        let mut written = 0;
+        self.visit(channel, start..start + out.len(), |samples| {
+            out[written..written + samples.len()].copy_from_slice(samples);
+            written += samples.len();
+        });
+        out[written..].iter_mut().for_each(|s| *s = 0.0);
+    }
+
+    pub fn get(&self, channel: usize, frame: usize) -> f32 {
+        let index = frame / PAGE_FRAMES;
+        self.page(channel, index)[frame - index * PAGE_FRAMES]
+    }
+
+    fn pieces(&self, channel: usize) -> Vec<Piece> {
+        match self.source.pieces() {
+            Some(channels) => channels[channel].clone(),
+            None if self.frames > 0 => {
+                vec![Piece { store: Some(self.clone()), channel, start: 0, length: self.frames, gain: 1.0 }]
+            },
+            None => vec![],
+        }
+    }
+
+    /// Frames `range` of `channels`, in that order, without copying them.
+    pub fn slice(&self, range: Range<usize>, channels: &[usize]) -> Self {
+        let range = range.start.min(self.frames)..range.end.min(self.frames);
+        Self::from_pieces(channels.iter().map(|&c| cut(&self.pieces(c), range.clone())).collect())
+    }
+
+    /// Replaces frames `range` of the channels set in `mask` with the channels of `insert`, in
+    /// order. Channels stay the same length, so `insert` must be as long as `range` unless
+    /// every channel is replaced.
+    pub fn splice(&self, range: Range<usize>, insert: &SampleStore, mask: &[bool]) -> Self {
+        debug_assert!(mask.iter().all(|&m| m) || insert.frames() == range.len());
+        let mut inserted = 0..insert.channels();
+        Self::from_pieces((0..self.channels)
+            .map(|c| {
+                let pieces = self.pieces(c);
+                match mask.get(c) {
+                    Some(true) => {
+                        let mut spliced = cut(&pieces, 0..range.start);
+                        spliced.extend(insert.pieces(inserted.next().unwrap_or(0)));
+                        spliced.extend(cut(&pieces, range.end..self.frames));
+                        spliced
+                    },
+                    _ => pieces,
+                }
+            })
+            .collect())
+    }
+
+    /// Multiplies frames `range` of the channels set in `mask` by `gain`, without copying them.
+    pub fn amplify(&self, range: Range<usize>, gain: f32, mask: &[bool]) -> Self {
+        let range = range.start.min(self.frames)..range.end.min(self.frames);
+        let amplified = Self::from_pieces((0..self.channels)
+            .filter(|&c| mask.get(c).copied().unwrap_or(false))
+            .map(|c| {
+                cut(&self.pieces(c), range.clone())
+                    .into_iter()
+                    .map(|piece| Piece { gain: piece.gain * gain, ..piece })
+                    .collect()
+            })
+            .collect());
+        self.splice(range, &amplified, mask)
+    }
+
+    /// Copies a whole channel out of the store.
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        let mut samples = vec![0.0; self.frames];
+        self.read(channel, 0, &mut samples);
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_across_pages() {
+        let frames = PAGE_FRAMES * 2 + 10;
+        let samples: Vec<f32> = (0..frames * 2).map(|i| i as f32).collect();
+        let store = SampleStore::from_interleaved(samples, 2);
+        assert_eq!(store.frames(), frames);
+
+        let mut out = vec![1.0; 20];
+        store.read(1, PAGE_FRAMES - 5, &mut out);
+        assert_eq!(out[0], ((PAGE_FRAMES - 5) * 2 + 1) as f32);
+        assert_eq!(out[5], (PAGE_FRAMES * 2 + 1) as f32);
+
+        // Past the end is silence
+        store.read(0, frames - 2, &mut out);
+        assert_eq!(out[1], ((frames - 1) * 2) as f32);
+        assert!(out[2..].iter().all(|&s| s == 0.0));
+
+        let mut visited = 0;
+        store.visit(0, 0..frames, |samples| visited += samples.len());
+        assert_eq!(visited, frames);
+        // Two pages of the second channel, all three of the first
+        assert_eq!(store.cache.lock().unwrap().pages.len(), 5);
+    }
+
+    #[test]
+    fn test_splice() {
+        let store = SampleStore::from_planar((0..20).map(|i| i as f32).collect(), 2);
+
+        // Cut frames 2..4 of both channels and put them back at the end
+        let clip = store.slice(2..4, &[0, 1]);
+        let cut = store.splice(2..4, &SampleStore::silence(0, 2), &[true, true]);
+        let moved = cut.splice(8..8, &clip, &[true, true]);
+        assert_eq!(moved.channel(0), [0.0, 1.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 2.0, 3.0]);
+        assert_eq!(moved.channel(1), [10.0, 11.0, 14.0, 15.0, 16.0, 17.0, 18.0, 19.0, 12.0, 13.0]);
+
+        // Silence part of the second channel only, the source isn't touched
+        let silenced = moved.splice(1..3, &SampleStore::silence(2, 1), &[false, true]);
+        assert_eq!(silenced.channel(0), moved.channel(0));
+        assert_eq!(&silenced.channel(1)[..4], [10.0, 0.0, 0.0, 15.0]);
+        assert_eq!(store.channel(1)[1..3], [11.0, 12.0]);
+
+        let amplified = silenced.amplify(0..2, 0.5, &[true, false]).amplify(1..3, 2.0, &[true, false]);
+        assert_eq!(&amplified.channel(0)[..4], [0.0, 1.0, 8.0, 5.0]);
+        assert_eq!(amplified.channel(1), silenced.channel(1));
+    }
+}
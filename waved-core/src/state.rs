@@ -1,13 +1,413 @@
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+use crate::editor::Editor;
+use crate::history::History;
+use crate::peaks::PeakPyramid;
+use crate::samples::SampleStore;
+
+/// Sample encoding of a file on disk, kept around to save it back the way it was loaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    Int8,
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl BitDepth {
+    pub fn bits(self) -> u16 {
+        match self {
+            BitDepth::Int8 => 8,
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Int32 | BitDepth::Float32 => 32,
+        }
+    }
+}
+
+/// Named position in a file, in frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    pub position: usize,
+    pub name: String,
+}
+
+/// Broadcast Wave `bext` chunk, as specified by EBU Tech 3285.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BroadcastExtension {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    /// `yyyy-mm-dd`
+    pub origination_date: String,
+    /// `hh:mm:ss`
+    pub origination_time: String,
+    /// Frames between midnight and the start of the file, what takes get synced by.
+    pub time_reference: u64,
+    pub version: u16,
+    /// SMPTE UMID, 64 bytes that are all zero when unset.
+    pub umid: Vec<u8>,
+    /// Integrated loudness, loudness range, maximum true peak, maximum momentary and maximum
+    /// short-term loudness, in hundredths. Only meaningful from version 2 on.
+    pub loudness: [i16; 5],
+    pub coding_history: String,
+}
+
+/// Chunk that nothing knows how to interpret, kept byte for byte.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// Metadata of the container besides tags, markers and loop points, carried along so that saving
+/// a file doesn't strip it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub bext: Option<BroadcastExtension>,
+    /// iXML document, kept as text to write it back untouched.
+    pub ixml: Option<String>,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Metadata {
+    /// Text of the first `<name>` element of the iXML document, `SCENE` or `TAKE` for instance.
+    pub fn ixml_value(&self, name: &str) -> Option<&str> {
+        let ixml = self.ixml.as_deref()?;
+        let start = ixml.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = start + ixml[start..].find(&format!("</{}>", name))?;
+        Some(ixml[start..end].trim())
+    }
+}
+
+#[derive(Clone)]
 pub struct AudioFile {
     pub filename: PathBuf,
-    pub samples: Vec<f32>,
+    pub samples: SampleStore,
     pub channels: u16,
     pub sample_rate: u32,
+    pub bit_depth: BitDepth,
+    /// Free-form `(name, value)` metadata such as Vorbis comments, names can repeat.
+    pub tags: Vec<(String, String)>,
+    pub markers: Vec<Marker>,
+    /// Start and end frames of the loop stored in the file, as used by samplers.
+    pub loop_points: Option<(usize, usize)>,
+    pub metadata: Metadata,
+    /// Whether the file was imported as headerless PCM, which there's no header to save back as.
+    /// It can only be saved under another name.
+    pub headerless: bool,
+}
+
+impl AudioFile {
+    /// A file of `samples` that wasn't loaded from anywhere, with no metadata. It's saved as
+    /// floats unless given another bit depth.
+    pub fn from_store(samples: SampleStore, sample_rate: u32) -> Self {
+        Self {
+            filename: PathBuf::new(),
+            channels: samples.channels() as u16,
+            samples,
+            sample_rate,
+            bit_depth: BitDepth::Float32,
+            tags: vec![],
+            markers: vec![],
+            loop_points: None,
+            metadata: Metadata::default(),
+            headerless: false,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.frames()
+    }
+
+    /// The file with `samples`, in which `removed` frames from `start` were replaced with
+    /// `inserted` frames. Markers and loop points after the edit move along with the frames,
+    /// markers in removed frames go away with them.
+    pub fn edited(&self, samples: SampleStore, start: usize, removed: usize, inserted: usize) -> Self {
+        let moved = |position: usize| {
+            if position >= start + removed {
+                Some(position - removed + inserted)
+            } else if position < start || removed == inserted {
+                Some(position)
+            } else {
+                None
+            }
+        };
+        Self {
+            samples,
+            markers: self.markers.iter()
+                .filter_map(|marker| Some(Marker { position: moved(marker.position)?, name: marker.name.clone() }))
+                .collect(),
+            loop_points: self.loop_points.and_then(|(loop_start, loop_end)| {
+                let loop_start = moved(loop_start).unwrap_or(start);
+                let loop_end = moved(loop_end).unwrap_or(start);
+                (loop_start < loop_end).then_some((loop_start, loop_end))
+            }),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransportStatus {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Snapshot of the audio thread's transport, refreshed once per frame.
+#[derive(Clone, Copy, Default)]
+pub struct Playback {
+    pub position: usize,
+    pub status: TransportStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadStage {
+    /// Parsing the file, and decoding it if it's compressed.
+    Decoding,
+    /// Reading the samples through to compute their peaks.
+    Scanning,
+}
+
+/// File being loaded in the background.
+pub struct Loading {
+    pub filename: PathBuf,
+    pub stage: LoadStage,
+    /// Fraction of the current stage done.
+    pub progress: f32,
+    /// Length of the file, known once it has been decoded.
+    pub frames: usize,
+    /// Peaks of the frames scanned so far.
+    pub peaks: PeakPyramid,
+}
+
+/// Shortest part of a file the view can be zoomed in to.
+const MIN_VIEW_FRAMES: usize = 64;
+
+/// Part of the current file shown across the window.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct View {
+    pub start: usize,
+    /// Frames shown, the whole file when 0.
+    pub length: usize,
+}
+
+impl View {
+    /// Frames shown of a file of `frames` frames.
+    pub fn range(&self, frames: usize) -> Range<usize> {
+        if self.length == 0 {
+            0..frames
+        } else {
+            self.start.min(frames)..(self.start + self.length).min(frames)
+        }
+    }
+
+    /// Scales the length of the view by `factor`, keeping `anchor` where it is on screen when
+    /// it's in view.
+    pub fn zoom(&mut self, factor: f32, anchor: usize, frames: usize) {
+        let range = self.range(frames);
+        if range.is_empty() {
+            return;
+        }
+        let length = ((range.len() as f32 * factor) as usize).clamp(MIN_VIEW_FRAMES.min(frames), frames);
+        let anchor = anchor.clamp(range.start, range.end);
+        let offset = ((anchor - range.start) as f32 / range.len() as f32 * length as f32) as usize;
+        self.start = anchor.saturating_sub(offset).min(frames - length);
+        self.length = length;
+    }
+
+    /// Moves the view by `amount` times its length, staying within the file.
+    pub fn scroll(&mut self, amount: f32, frames: usize) {
+        let range = self.range(frames);
+        let start = range.start as f32 + range.len() as f32 * amount;
+        self.start = (start.max(0.0) as usize).min(frames - range.len());
+        self.length = range.len();
+    }
+
+    /// Frame shown `fraction` of the way across the view.
+    pub fn frame_at(&self, fraction: f32, frames: usize) -> usize {
+        let range = self.range(frames);
+        (range.start + (fraction.clamp(0.0, 1.0) * range.len() as f32) as usize).min(frames)
+    }
+}
+
+/// Frames selected in some of the channels of the current file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub start: usize,
+    pub end: usize,
+    /// Whether each channel is part of the selection.
+    pub channels: Vec<bool>,
+}
+
+impl Selection {
+    /// Selects `range` in all of `channels`.
+    pub fn new(range: Range<usize>, channels: usize) -> Self {
+        Self { start: range.start, end: range.end.max(range.start), channels: vec![true; channels] }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end || !self.channels.contains(&true)
+    }
+
+    pub fn contains_channel(&self, channel: usize) -> bool {
+        self.channels.get(channel).copied().unwrap_or(false)
+    }
+
+    pub fn toggle_channel(&mut self, channel: usize) {
+        if let Some(selected) = self.channels.get_mut(channel) {
+            *selected = !*selected;
+        }
+    }
+
+    /// Grows the selection up to `frame`.
+    pub fn extend(&mut self, frame: usize) {
+        self.start = self.start.min(frame);
+        self.end = self.end.max(frame);
+    }
+
+    /// Pulls both ends of the selection in by `frames`, down to nothing.
+    pub fn shrink(&mut self, frames: usize) {
+        let frames = frames.min(self.range().len() / 2);
+        self.start += frames;
+        self.end -= frames;
+    }
+}
+
+/// Options changed with `:set`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Beats per minute, what beat motions move by.
+    pub tempo: f32,
+    /// Shortest silence silence motions stop at, in seconds.
+    pub min_silence: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { tempo: 120.0, min_silence: 0.1 }
+    }
 }
 
 #[derive(Default)]
 pub struct State {
-    pub current_file: Option<AudioFile>,
+    // Shared with the audio thread, which reads from it during playback.
+    pub current_file: Option<Arc<AudioFile>>,
+    /// Peaks of `current_file`, what the waveform is drawn from.
+    pub peaks: PeakPyramid,
+    pub view: View,
+    /// Where edits and playback start from, in frames.
+    pub cursor: usize,
+    pub selection: Option<Selection>,
+    pub editor: Editor,
+    /// Edits of `current_file`, to undo and redo them.
+    pub history: History,
+    pub settings: Settings,
+    /// Whether `current_file` was edited since it was loaded or saved.
+    pub modified: bool,
+    pub loading: Option<Loading>,
+    pub playback: Playback,
+    pub looping: bool,
+    /// Whether the metadata of the current file is shown over the waveform.
+    pub show_metadata: bool,
+    /// Last message logged, displayed in the status bar.
+    pub message: Option<String>,
+}
+
+impl State {
+    pub(crate) fn frames(&self) -> usize {
+        self.current_file.as_ref().map_or(0, |file| file.frames())
+    }
+
+    pub(crate) fn channels(&self) -> usize {
+        self.current_file.as_ref().map_or(0, |file| file.channels as usize)
+    }
+
+    pub fn set_cursor(&mut self, frame: usize) {
+        self.cursor = frame.min(self.frames());
+    }
+
+    /// Selects `range` in every channel, an empty range clears the selection.
+    pub fn select(&mut self, range: Range<usize>) {
+        let frames = self.frames();
+        let range = range.start.min(frames)..range.end.min(frames);
+        self.selection = (!range.is_empty()).then(|| Selection::new(range, self.channels()));
+    }
+
+    /// Grows the selection up to `frame` and moves the cursor there, selecting from the cursor
+    /// when nothing is selected yet.
+    pub fn extend_selection(&mut self, frame: usize) {
+        let frame = frame.min(self.frames());
+        match &mut self.selection {
+            Some(selection) => selection.extend(frame),
+            None => self.select(self.cursor.min(frame)..self.cursor.max(frame)),
+        }
+        self.cursor = frame;
+    }
+
+    /// Pulls both ends of the selection in by `frames`, clearing it once nothing's left.
+    pub fn shrink_selection(&mut self, frames: usize) {
+        if let Some(selection) = &mut self.selection {
+            selection.shrink(frames);
+            if selection.is_empty() {
+                self.selection = None;
+            }
+        }
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection() {
+        let file = Arc::new(AudioFile::from_store(SampleStore::from_planar(vec![0.0; 2000], 2), 44100));
+        let mut state = State { current_file: Some(file), ..Default::default() };
+
+        state.set_cursor(500);
+        state.extend_selection(200);
+        state.extend_selection(800);
+        assert_eq!(state.selection.as_ref().unwrap().range(), 200..800);
+        assert_eq!(state.cursor, 800);
+
+        state.extend_selection(5000);
+        assert_eq!(state.selection.as_ref().unwrap().range(), 200..1000);
+
+        state.selection.as_mut().unwrap().toggle_channel(1);
+        assert!(!state.selection.as_ref().unwrap().contains_channel(1));
+        state.shrink_selection(100);
+        assert_eq!(state.selection.as_ref().unwrap().range(), 300..900);
+        state.shrink_selection(1000);
+        assert_eq!(state.selection, None);
+    }
+
+    #[test]
+    fn test_view_zoom() {
+        let mut view = View::default();
+        view.zoom(0.5, 1000, 4000);
+        assert_eq!(view.range(4000), 500..2500);
+
+        view.zoom(0.001, 1000, 4000);
+        assert_eq!(view.range(4000).len(), MIN_VIEW_FRAMES);
+        view.zoom(100.0, 1000, 4000);
+        assert_eq!(view.range(4000), 0..4000);
+
+        view.zoom(0.25, 0, 4000);
+        view.scroll(10.0, 4000);
+        assert_eq!(view.range(4000), 3000..4000);
+    }
 }
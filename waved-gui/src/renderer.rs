@@ -1,10 +1,11 @@
-use nanovg::{Color, Context, ContextBuilder, Font, Frame, StrokeOptions};
+use nanovg::{Alignment, Color, Context, ContextBuilder, Font, Frame, StrokeOptions, TextOptions};
 
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 
-use waved_core::state::State;
+use waved_core::editor::Mode;
+use waved_core::peaks::{Peak, PEAK_FRAMES};
+use waved_core::state::{AudioFile, LoadStage, State, TransportStatus};
 
-#[allow(dead_code)]
 pub struct Fonts<'f> {
     regular: Font<'f>,
     bold: Font<'f>,
@@ -16,12 +17,12 @@ pub struct Renderer<'f> {
     fonts: Fonts<'f>,
 }
 
-fn draw_line(frame: &Frame, from: (f32, f32), to: (f32, f32)) {
+fn draw_line(frame: &Frame, from: (f32, f32), to: (f32, f32), color: Color) {
     frame.path(|path| {
         path.move_to(from);
         path.line_to(to);
         path.stroke(
-            Color::from_rgba(255, 255, 255, 255),
+            color,
             StrokeOptions {
                 width: 1.0,
                 ..Default::default()
@@ -30,60 +31,182 @@ fn draw_line(frame: &Frame, from: (f32, f32), to: (f32, f32)) {
     }, Default::default());
 }
 
-fn draw_waveform(frame: &Frame, pos: (f32, f32), size: (f32, f32), samples: &[f32]) {
+/// Draws frames `view` of a channel, `peak` giving the peak of a range of frames if it has one
+/// yet.
+fn draw_waveform<F>(frame: &Frame, pos: (f32, f32), size: (f32, f32), view: Range<usize>, peak: F)
+where
+    F: Fn(Range<usize>) -> Option<Peak>,
+{
     let width = size.0;
-    let height = size.1;
-    let half_height  = height * 0.5;
+    let half_height = size.1 * 0.5;
     let top = pos.1;
     let left = pos.0;
+    let center = top + half_height;
 
-    let draw_segment = |x: f32, weight: (f32, f32)| {
-        let line_height = (half_height * weight.0, half_height * weight.1);
-        let line_start_y = top + half_height - line_height.1;
-        let line_end_y = top + half_height + line_height.0;
-        draw_line(frame, (left + x, line_start_y), (left + x, line_end_y));
-    };
+    draw_line(frame, (left, center), (left + width, center), Color::from_rgba(255, 255, 255, 255));
 
-    draw_line(frame, (left, top + half_height), (left + width, top + half_height));
+    let frames_per_pixel = view.len() as f32 / width;
+    for x in 0..width.ceil() as usize {
+        let first = view.start + (x as f32 * frames_per_pixel) as usize;
+        let last = (view.start + ((x + 1) as f32 * frames_per_pixel) as usize).max(first + 1);
+        let peak = match peak(first..last.min(view.end)) {
+            Some(peak) => peak,
+            None => continue,
+        };
+        let x = left + x as f32;
+        draw_line(frame, (x, center - peak.max * half_height), (x, center - peak.min * half_height),
+            Color::from_rgba(255, 255, 255, 96));
+        draw_line(frame, (x, center - peak.rms * half_height), (x, center + peak.rms * half_height),
+            Color::from_rgba(255, 255, 255, 255));
+    }
+}
 
-    let mut current_x = 0;
-    let mut sum_of_squares = (0.0, 0.0);
-    let mut num_samples_acc = (0, 0);
+/// Horizontal position of `position` in frames, when it's in view.
+fn x_of(pos: (f32, f32), size: (f32, f32), position: usize, view: &Range<usize>) -> f32 {
+    pos.0 + (position as f32 - view.start as f32) / view.len() as f32 * size.0
+}
 
-    let num_samples = samples.len();
-    for (i, s) in samples.iter().enumerate() {
-        let x = (i as f32 / num_samples as f32 * width).round() as i32;
-        if current_x == x {
-            if *s < 0.0 {
-                sum_of_squares.0 += s * s;
-                num_samples_acc.0 += 1;
-            } else {
-                sum_of_squares.1 += s * s;
-                num_samples_acc.1 += 1;
-            }
-        } else {
-            draw_segment(x as f32, (
-                (sum_of_squares.0 / num_samples_acc.0 as f32).sqrt(),
-                (sum_of_squares.1 / num_samples_acc.1 as f32).sqrt()
-            ));
-            current_x = x;
-            sum_of_squares = (0.0, 0.0);
-            num_samples_acc = (0, 0);
-        }
+fn draw_selection(frame: &Frame, pos: (f32, f32), size: (f32, f32), selection: Range<usize>, view: &Range<usize>) {
+    let start = x_of(pos, size, selection.start, view).max(pos.0);
+    let end = x_of(pos, size, selection.end, view).min(pos.0 + size.0);
+    if start >= end {
+        return;
     }
 
-    draw_segment(width, (
-        (sum_of_squares.0 / num_samples_acc.0 as f32).sqrt(),
-        (sum_of_squares.1 / num_samples_acc.1 as f32).sqrt()
-    ));
+    frame.path(|path| {
+        path.rect((start, pos.1), (end - start, size.1));
+        path.fill(Color::from_rgba(80, 140, 255, 80), Default::default());
+    }, Default::default());
+}
+
+/// Draws a vertical line at `position`, like the playhead or the cursor.
+fn draw_position(frame: &Frame, pos: (f32, f32), size: (f32, f32), position: usize, view: &Range<usize>, color: Color) {
+    if !view.contains(&position) {
+        return;
+    }
+
+    let x = x_of(pos, size, position, view);
+    frame.path(|path| {
+        path.move_to((x, pos.1));
+        path.line_to((x, pos.1 + size.1));
+        path.stroke(
+            color,
+            StrokeOptions {
+                width: 1.0,
+                ..Default::default()
+            }
+        );
+    }, Default::default());
+}
+
+fn format_time(frames: usize, sample_rate: u32) -> String {
+    let millis = frames as u64 * 1000 / sample_rate as u64;
+    format!("{:02}:{:02}.{:03}", millis / 60_000, millis / 1000 % 60, millis % 1000)
 }
 
-fn draw_status_bar(frame: &Frame, pos: (f32, f32), size: (f32, f32)) {
-    // TODO: Add command line similar to vim
+fn draw_status_bar(frame: &Frame, font: Font, pos: (f32, f32), size: (f32, f32), text: &str, message: &str) {
+    frame.path(|path| {
+        path.rect(pos, size);
+        path.fill(Color::from_rgba(255, 255, 255, 255), Default::default());
+    }, Default::default());
+
+    frame.text(font, (pos.0 + 4.0, pos.1 + size.1 * 0.5), text, TextOptions {
+        color: Color::from_rgba(0, 0, 0, 255),
+        size: 14.0,
+        align: Alignment::new().left().middle(),
+        ..Default::default()
+    });
+
+    frame.text(font, (pos.0 + size.0 - 4.0, pos.1 + size.1 * 0.5), message, TextOptions {
+        color: Color::from_rgba(160, 0, 0, 255),
+        size: 14.0,
+        align: Alignment::new().right().middle(),
+        ..Default::default()
+    });
+}
+
+/// Draws the command line being typed in place of the status bar.
+fn draw_command_line(frame: &Frame, fonts: &Fonts, pos: (f32, f32), size: (f32, f32), text: &str) {
+    const FONT_SIZE: f32 = 14.0;
+    // Inconsolata is monospaced, every character is half as wide as the font is high
+    const CHAR_WIDTH: f32 = FONT_SIZE * 0.5;
+
     frame.path(|path| {
         path.rect(pos, size);
         path.fill(Color::from_rgba(255, 255, 255, 255), Default::default());
     }, Default::default());
+
+    let options = |color| TextOptions {
+        color,
+        size: FONT_SIZE,
+        align: Alignment::new().left().middle(),
+        ..Default::default()
+    };
+    let middle = pos.1 + size.1 * 0.5;
+    frame.text(fonts.bold, (pos.0 + 4.0, middle), ":", options(Color::from_rgba(0, 0, 0, 255)));
+    frame.text(fonts.regular, (pos.0 + 4.0 + CHAR_WIDTH, middle), text, options(Color::from_rgba(0, 0, 0, 255)));
+
+    let caret = pos.0 + 4.0 + CHAR_WIDTH * (text.chars().count() + 1) as f32;
+    frame.path(|path| {
+        path.rect((caret, middle - FONT_SIZE * 0.5), (CHAR_WIDTH, FONT_SIZE));
+        path.fill(Color::from_rgba(0, 0, 0, 160), Default::default());
+    }, Default::default());
+}
+
+/// One line per piece of metadata, empty values left out.
+fn metadata_lines(file: &AudioFile) -> Vec<String> {
+    let mut lines = vec![];
+    let mut add = |name: &str, value: &str| {
+        if !value.is_empty() {
+            lines.push(format!("{}: {}", name, value));
+        }
+    };
+
+    if let Some(bext) = &file.metadata.bext {
+        add("Description", &bext.description);
+        add("Originator", &bext.originator);
+        add("Reference", &bext.originator_reference);
+        add("Origination", format!("{} {}", bext.origination_date, bext.origination_time).trim());
+        // Frames since midnight
+        let seconds = bext.time_reference / file.sample_rate as u64;
+        add("Time reference", &format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60));
+    }
+    for &name in &["PROJECT", "SCENE", "TAKE", "TAPE", "NOTE"] {
+        add(name, file.metadata.ixml_value(name).unwrap_or(""));
+    }
+    for (name, value) in &file.tags {
+        add(name, value);
+    }
+    for marker in &file.markers {
+        add("Marker", format!("{} {}", format_time(marker.position, file.sample_rate), marker.name).trim());
+    }
+    let chunks: Vec<_> = file.metadata.chunks.iter().map(|c| String::from_utf8_lossy(&c.id).into_owned()).collect();
+    add("Other chunks", &chunks.join(", "));
+    lines
+}
+
+fn draw_metadata(frame: &Frame, font: Font, pos: (f32, f32), size: (f32, f32), file: &AudioFile) {
+    const LINE_HEIGHT: f32 = 16.0;
+
+    let mut lines = metadata_lines(file);
+    if lines.is_empty() {
+        lines.push("No metadata".to_string());
+    }
+
+    frame.path(|path| {
+        path.rect(pos, size);
+        path.fill(Color::from_rgba(0, 0, 0, 200), Default::default());
+    }, Default::default());
+
+    let visible = ((size.1 - 8.0) / LINE_HEIGHT).max(0.0) as usize;
+    for (i, line) in lines.iter().take(visible).enumerate() {
+        frame.text(font, (pos.0 + 8.0, pos.1 + 4.0 + i as f32 * LINE_HEIGHT), line, TextOptions {
+            color: Color::from_rgba(255, 255, 255, 255),
+            size: 14.0,
+            align: Alignment::new().left().top(),
+            ..Default::default()
+        });
+    }
 }
 
 impl<'f> Renderer<'f> {
@@ -116,23 +239,77 @@ impl<'f> Renderer<'f> {
     pub fn render(&self, state: &State, viewport: (f32, f32), scale: f32) {
         self.context.frame(viewport, scale, |frame| {
             const STATUS_BAR_HEIGHT: f32 = 20.0;
-            draw_status_bar(&frame, (0.0, viewport.1 - STATUS_BAR_HEIGHT), (viewport.0, viewport.1));
 
-            if let Some(file) = &state.current_file {
+            let status_text = match (&state.loading, &state.current_file) {
+                (Some(loading), _) => {
+                    let stage = match loading.stage {
+                        LoadStage::Decoding => "DECODING",
+                        LoadStage::Scanning => "SCANNING",
+                    };
+                    format!("{} {:.0}% {} (Esc to cancel)", stage, loading.progress * 100.0,
+                        loading.filename.file_name().unwrap_or_default().to_string_lossy())
+                },
+                (None, Some(file)) => {
+                    let status = match state.playback.status {
+                        TransportStatus::Playing => "PLAYING",
+                        TransportStatus::Paused => "PAUSED",
+                        TransportStatus::Stopped => "STOPPED",
+                    };
+                    format!("{} {} {} / {}{}{} {}", state.editor.mode.name(), status,
+                        format_time(state.playback.position, file.sample_rate),
+                        format_time(file.frames(), file.sample_rate),
+                        if state.looping { " [LOOP]" } else { "" },
+                        if state.modified { " [+]" } else { "" },
+                        state.editor.pending)
+                },
+                (None, None) => format!("{} {}", state.editor.mode.name(), state.editor.pending),
+            };
+            if state.editor.mode == Mode::Command {
+                draw_command_line(&frame, &self.fonts, (0.0, viewport.1 - STATUS_BAR_HEIGHT), (viewport.0, STATUS_BAR_HEIGHT),
+                    &state.editor.command_line);
+            } else {
+                draw_status_bar(&frame, self.fonts.regular, (0.0, viewport.1 - STATUS_BAR_HEIGHT), (viewport.0, STATUS_BAR_HEIGHT), &status_text,
+                    state.message.as_deref().unwrap_or(""));
+            }
+
+            if let Some(loading) = &state.loading {
+                // Fill the waveform in as the peaks come
+                let channels = loading.peaks.base().channels.len().max(1);
+                let channel_height = (viewport.1 - STATUS_BAR_HEIGHT) / channels as f32;
+                for i in 0..loading.peaks.base().channels.len() {
+                    draw_waveform(&frame, (0.0, i as f32 * channel_height), (viewport.0, channel_height), 0..loading.frames,
+                        |range| loading.peaks.peak(i, range));
+                }
+            } else if let Some(file) = &state.current_file {
                 let channel_height = (viewport.1 - STATUS_BAR_HEIGHT) / file.channels as f32;
 
-                for i in 0..file.channels as usize {
-                    let samples_per_channel = file.samples.len() / file.channels as usize;
-                    let channel_slice_start = samples_per_channel * i;
-                    let channel_slice_end = samples_per_channel * (i + 1);
+                let view = state.view.range(file.frames());
 
+                for i in 0..file.channels as usize {
+                    if let Some(selection) = state.selection.as_ref().filter(|s| s.contains_channel(i)) {
+                        draw_selection(&frame, (0.0, i as f32 * channel_height), (viewport.0, channel_height), selection.range(), &view);
+                    }
                     draw_waveform(
                         &frame,
                         (0.0, i as f32 * channel_height),
                         (viewport.0, channel_height),
-                        &file.samples[channel_slice_start..channel_slice_end]
+                        view.clone(),
+                        // Zoomed in past the peaks, the few samples per pixel are cheap to read
+                        |range| if range.len() < PEAK_FRAMES {
+                            Peak::scan(&file.samples, i, range)
+                        } else {
+                            state.peaks.peak(i, range)
+                        }
                     );
                 }
+
+                let area = (viewport.0, viewport.1 - STATUS_BAR_HEIGHT);
+                draw_position(&frame, (0.0, 0.0), area, state.cursor, &view, Color::from_rgba(80, 140, 255, 255));
+                draw_position(&frame, (0.0, 0.0), area, state.playback.position, &view, Color::from_rgba(255, 160, 0, 255));
+
+                if state.show_metadata {
+                    draw_metadata(&frame, self.fonts.regular, (0.0, 0.0), (viewport.0, viewport.1 - STATUS_BAR_HEIGHT), file);
+                }
             }
         });
     }
@@ -1,7 +1,5 @@
-#[allow(dead_code)]
-
 pub fn interleave<T: Copy>(slice: &[T], stride: usize) -> Vec<T> {
-    assert!(slice.len() % stride == 0);
+    assert!(slice.len().is_multiple_of(stride));
     let mut interleaved = Vec::with_capacity(slice.len());
     let stride_len = slice.len() / stride;
     for i in 0..stride_len {
@@ -13,7 +11,7 @@ pub fn interleave<T: Copy>(slice: &[T], stride: usize) -> Vec<T> {
 }
 
 pub fn deinterleave<T: Copy>(slice: &[T], stride: usize) -> Vec<T> {
-    assert!(slice.len() % stride == 0);
+    assert!(slice.len().is_multiple_of(stride));
     let mut deinterleaved = Vec::with_capacity(slice.len());
     let stride_len = slice.len() / stride;
     for i in 0..stride {
@@ -0,0 +1,136 @@
+/// Strategy used when a file has more channels than the output device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Downmix {
+    /// Wrap extra channels around the outputs, averaging the ones that share an output.
+    #[default]
+    Fold,
+    /// Only keep the first channels, dropping the ones the device can't output.
+    Truncate,
+    /// Treat 6 channel files as 5.1 (L, R, C, LFE, Ls, Rs) and apply an ITU-R BS.775 stereo
+    /// downmix, falling back to `Fold` for any other layout.
+    Surround,
+}
+
+/// Gain matrix routing the channels of a file onto the channels of a device.
+pub struct ChannelMap {
+    inputs: usize,
+    outputs: usize,
+    gains: Vec<f32>,
+}
+
+impl ChannelMap {
+    pub fn new(inputs: usize, outputs: usize, downmix: Downmix) -> Self {
+        assert!(inputs > 0 && outputs > 0);
+        let mut map = Self { inputs, outputs, gains: vec![0.0; inputs * outputs] };
+
+        if inputs == 1 {
+            // Up-mix mono to the front speakers, any extra device channel stays silent.
+            for output in 0..outputs.min(2) {
+                map.set_gain(output, 0, 1.0);
+            }
+        } else if inputs <= outputs {
+            for channel in 0..inputs {
+                map.set_gain(channel, channel, 1.0);
+            }
+        } else {
+            match downmix {
+                Downmix::Surround if inputs == 6 && outputs >= 2 => {
+                    const CENTER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+                    const SURROUND: f32 = std::f32::consts::FRAC_1_SQRT_2;
+                    // Normalize so that a full scale signal on every channel can't clip.
+                    let norm = 1.0 / (1.0 + CENTER + SURROUND);
+                    map.set_gain(0, 0, norm);
+                    map.set_gain(1, 1, norm);
+                    map.set_gain(0, 2, CENTER * norm);
+                    map.set_gain(1, 2, CENTER * norm);
+                    map.set_gain(0, 4, SURROUND * norm);
+                    map.set_gain(1, 5, SURROUND * norm);
+                },
+                Downmix::Truncate => {
+                    for channel in 0..outputs {
+                        map.set_gain(channel, channel, 1.0);
+                    }
+                },
+                Downmix::Fold | Downmix::Surround => {
+                    for output in 0..outputs {
+                        let sources = (output..inputs).step_by(outputs);
+                        let gain = 1.0 / sources.len() as f32;
+                        for input in sources {
+                            map.set_gain(output, input, gain);
+                        }
+                    }
+                },
+            }
+        }
+
+        map
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    pub fn gain(&self, output: usize, input: usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    fn set_gain(&mut self, output: usize, input: usize, gain: f32) {
+        self.gains[output * self.inputs + input] = gain;
+    }
+
+    /// Maps a single frame of `inputs` samples into a frame of `outputs` samples.
+    pub fn apply(&self, input: &[f32], output: &mut [f32]) {
+        debug_assert_eq!(input.len(), self.inputs);
+        debug_assert_eq!(output.len(), self.outputs);
+        for (o, out) in output.iter_mut().enumerate() {
+            let gains = &self.gains[o * self.inputs..(o + 1) * self.inputs];
+            *out = gains.iter().zip(input).map(|(g, s)| g * s).sum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_frame(map: &ChannelMap, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0; map.outputs()];
+        map.apply(input, &mut output);
+        output
+    }
+
+    #[test]
+    fn test_mono_upmix() {
+        let map = ChannelMap::new(1, 4, Downmix::Fold);
+        assert_eq!(map_frame(&map, &[0.5]), [0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_fold_downmix() {
+        let map = ChannelMap::new(2, 1, Downmix::Fold);
+        assert_eq!(map_frame(&map, &[1.0, 0.0]), [0.5]);
+
+        let map = ChannelMap::new(3, 2, Downmix::Fold);
+        assert_eq!(map_frame(&map, &[1.0, 0.2, 0.6]), [0.8, 0.2]);
+    }
+
+    #[test]
+    fn test_truncate_downmix() {
+        let map = ChannelMap::new(4, 2, Downmix::Truncate);
+        assert_eq!(map_frame(&map, &[0.1, 0.2, 0.3, 0.4]), [0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_surround_downmix() {
+        let map = ChannelMap::new(6, 2, Downmix::Surround);
+        let output = map_frame(&map, &[1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        assert!((output[0] - 1.0).abs() < 1e-6);
+        assert!((output[1] - 1.0).abs() < 1e-6);
+        // The LFE channel is not part of the stereo downmix
+        assert_eq!(map.gain(0, 3), 0.0);
+    }
+}
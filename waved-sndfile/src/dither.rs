@@ -0,0 +1,165 @@
+/// Xorshift generator, dithered output can be reproduced exactly from the same seed.
+#[derive(Clone)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift gets stuck on a zero state.
+        Self { state: if seed == 0 { 0x9e37_79b9 } else { seed } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dither {
+    /// Plain rounding to the nearest integer.
+    #[default]
+    None,
+    /// Triangular probability density noise of +/- 1 LSB, decorrelates the quantization error
+    /// from the signal.
+    Tpdf,
+}
+
+/// Filter applied to the quantization error, moving it to where the ear is less sensitive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NoiseShaping {
+    #[default]
+    None,
+    /// Simple error feedback, tilts the noise towards high frequencies.
+    FirstOrder,
+    /// Wannamaker's 3 tap F-weighted filter, which follows the ear's sensitivity more closely.
+    Wannamaker3,
+}
+
+impl NoiseShaping {
+    fn coefficients(self) -> &'static [f64] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::FirstOrder => &[1.0],
+            NoiseShaping::Wannamaker3 => &[1.623, -0.982, 0.109],
+        }
+    }
+}
+
+/// Converts samples in `[-1, 1]` to two's complement integers of `bits` bits.
+///
+/// Scaling mirrors the one used when loading integer files so that unedited samples survive a
+/// load/save cycle untouched. Noise shaping keeps state between samples, use one quantizer
+/// per channel.
+pub struct Quantizer {
+    neg_range: f64,
+    pos_range: f64,
+    dither: Dither,
+    coefficients: &'static [f64],
+    errors: [f64; 3],
+    rng: Rng,
+}
+
+impl Quantizer {
+    pub fn new(bits: u16, dither: Dither, noise_shaping: NoiseShaping, seed: u32) -> Self {
+        let neg_range = 2f64.powi(bits as i32 - 1);
+        Self {
+            neg_range,
+            pos_range: neg_range - 1.0,
+            dither,
+            coefficients: noise_shaping.coefficients(),
+            errors: [0.0; 3],
+            rng: Rng::new(seed),
+        }
+    }
+
+    pub fn quantize(&mut self, sample: f32) -> i32 {
+        let scaled = if sample < 0.0 {
+            sample as f64 * self.neg_range
+        } else {
+            sample as f64 * self.pos_range
+        };
+
+        let shaped = scaled - self.coefficients.iter()
+            .zip(&self.errors)
+            .map(|(c, e)| c * e)
+            .sum::<f64>();
+
+        let noise = match self.dither {
+            Dither::None => 0.0,
+            Dither::Tpdf => (self.rng.next_f32() - self.rng.next_f32()) as f64,
+        };
+
+        // The error is taken before clipping, an overdriven signal would otherwise feed back
+        // errors far larger than a step and drive the filter unstable.
+        let rounded = (shaped + noise).round();
+        if !self.coefficients.is_empty() {
+            self.errors.rotate_right(1);
+            self.errors[0] = rounded - shaped;
+        }
+        rounded.max(-self.neg_range).min(self.pos_range) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_without_dither() {
+        let mut quantizer = Quantizer::new(16, Dither::None, NoiseShaping::None, 0);
+        assert_eq!(quantizer.quantize(1.0), 32767);
+        assert_eq!(quantizer.quantize(-1.0), -32768);
+        assert_eq!(quantizer.quantize(2.0), 32767);
+        assert_eq!(quantizer.quantize(0.5 / 32767.0 * 0.9), 0);
+    }
+
+    #[test]
+    fn test_tpdf_dither_is_reproducible() {
+        let quantize = |seed| {
+            let mut quantizer = Quantizer::new(8, Dither::Tpdf, NoiseShaping::None, seed);
+            (0..64).map(|i| quantizer.quantize(i as f32 / 640.0)).collect::<Vec<_>>()
+        };
+        assert_eq!(quantize(1234), quantize(1234));
+        assert_ne!(quantize(1234), quantize(4321));
+
+        // The error stays within the +/- 1 LSB of dither plus rounding.
+        let mut quantizer = Quantizer::new(8, Dither::Tpdf, NoiseShaping::None, 1);
+        for _ in 0..1000 {
+            assert!((quantizer.quantize(0.25) - 32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_noise_shaping_preserves_level() {
+        // A constant signal between two steps comes out as a pattern averaging to that signal.
+        for &shaping in &[NoiseShaping::FirstOrder, NoiseShaping::Wannamaker3] {
+            let mut quantizer = Quantizer::new(8, Dither::Tpdf, shaping, 7);
+            let sum: i32 = (0..10000).map(|_| quantizer.quantize(10.3 / 127.0)).sum();
+            assert!((sum as f64 / 10000.0 - 10.3).abs() < 0.01, "{:?}", shaping);
+        }
+    }
+
+    #[test]
+    fn test_noise_shaping_survives_clipping() {
+        // An overdriven signal clips cleanly instead of setting off the filter.
+        for &shaping in &[NoiseShaping::FirstOrder, NoiseShaping::Wannamaker3] {
+            let mut quantizer = Quantizer::new(16, Dither::Tpdf, shaping, 3);
+            for i in 0..48000 {
+                let sample = 1.2 * (i as f32 * 0.05).sin();
+                let expected = sample.clamp(-1.0, 1.0) * 32767.0;
+                assert!((quantizer.quantize(sample) as f32 - expected).abs() < 8.0, "{:?}", shaping);
+            }
+        }
+    }
+}
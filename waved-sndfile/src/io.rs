@@ -1,34 +1,559 @@
-use hound::WavReader;
-pub use hound::{Error, Sample, SampleFormat};
-
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
 use std::path::Path;
 
-use crate::algorithm::deinterleave;
+use waved_core::samples::SampleStore;
+use waved_core::state::{AudioFile, BitDepth};
 
-pub fn samples_from_file<P: AsRef<Path>>(filename: P) -> Result<(Vec<f32>, u16, u32), Error> {
-    let mut reader = WavReader::open(filename)?;
-    let spec = reader.spec();
-    let samples: Vec<_> = match spec.sample_format {
-        SampleFormat::Float => {
-            reader.samples::<f32>()
-                .map(|s| s.unwrap())
-                .collect()
-        },
-        SampleFormat::Int => {
-            // The range values assume the integers are encoded using two's complement
-            let neg_range = 2i32.pow((spec.bits_per_sample - 1).into());
-            let pos_range = neg_range - 1;
-            reader.samples::<i32>()
-                .map(|s| { 
-                    let s = s.unwrap();
-                    if s < 0 {
-                        (s as f64 / neg_range as f64) as f32
-                    } else {
-                        (s as f64 / pos_range as f64) as f32
-                    }
-                })
-                .collect()
-        },
+use crate::dither::{Dither, NoiseShaping, Quantizer};
+
+mod aiff;
+mod flac;
+mod mapping;
+mod mp3;
+mod raw;
+mod vorbis;
+mod wav;
+
+pub use self::raw::{Endianness, RawFormat, RawLayout, RawLayoutError};
+
+pub enum LoadError {
+    /// The file isn't encoded in a format we know how to decode.
+    UnsupportedFormat(String),
+    /// The file ends before all the audio data announced by its header.
+    /// Whatever could be decoded up to that point is kept in `file`. Streams that don't announce
+    /// their length, like Ogg, report the recovered length as expected.
+    Truncated { file: Box<AudioFile>, expected_frames: usize },
+    Io(io::Error),
+    /// The header is malformed or describes inconsistent audio data.
+    BadHeader(String),
+}
+
+impl LoadError {
+    /// Audio that could still be salvaged from the file, if any.
+    pub fn into_recovered(self) -> Option<AudioFile> {
+        match self {
+            LoadError::Truncated { file, .. } => Some(*file),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedFormat(reason) => write!(f, "Unsupported format: {}", reason),
+            LoadError::Truncated { file, expected_frames } => write!(f,
+                "{} is truncated, recovered {} of {} frames",
+                file.filename.display(), file.frames(), expected_frames),
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::BadHeader(reason) => write!(f, "Bad header: {}", reason),
+        }
+    }
+}
+
+// Written by hand, a derived implementation would dump every recovered sample.
+impl fmt::Debug for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedFormat(reason) => f.debug_tuple("UnsupportedFormat").field(reason).finish(),
+            LoadError::Truncated { file, expected_frames } => f.debug_struct("Truncated")
+                .field("frames", &file.frames())
+                .field("expected_frames", expected_frames)
+                .finish(),
+            LoadError::Io(err) => f.debug_tuple("Io").field(err).finish(),
+            LoadError::BadHeader(reason) => f.debug_tuple("BadHeader").field(reason).finish(),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+/// Container formats files can be loaded from and saved to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    /// RIFF WAVE, along with its RF64 and BW64 extensions for files over 4 GB.
+    Wav,
+    /// Sony Wave64.
+    Wave64,
+    Flac,
+    /// AIFF and its AIFF-C extension.
+    Aiff,
+    /// Ogg Vorbis, decode only.
+    Vorbis,
+    /// MPEG audio layer III, decode only.
+    Mp3,
+}
+
+impl FileFormat {
+    /// Identifies a format from the first bytes of a file, its extension is never trusted.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if let Some(container) = wav::Container::detect(header) {
+            Some(if container == wav::Container::Wave64 { FileFormat::Wave64 } else { FileFormat::Wav })
+        } else if header.starts_with(b"fLaC") {
+            Some(FileFormat::Flac)
+        } else if header.len() >= 12 && &header[0..4] == b"FORM" && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC") {
+            Some(FileFormat::Aiff)
+        } else if header.starts_with(b"OggS") {
+            Some(FileFormat::Vorbis)
+        } else if header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0 && (header[1] >> 1) & 3 == 1 {
+            Some(FileFormat::Mp3)
+        } else {
+            None
+        }
+    }
+
+    /// Format matching the extension the user typed when saving a file. Defaults to WAV.
+    pub fn from_extension<P: AsRef<Path>>(filename: P) -> Self {
+        match filename.as_ref().extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("w64") => FileFormat::Wave64,
+            Some(ext) if ext.eq_ignore_ascii_case("flac") => FileFormat::Flac,
+            Some(ext) if ["aif", "aiff", "aifc"].iter().any(|e| ext.eq_ignore_ascii_case(e)) => FileFormat::Aiff,
+            Some(ext) if ext.eq_ignore_ascii_case("ogg") || ext.eq_ignore_ascii_case("oga") => FileFormat::Vorbis,
+            Some(ext) if ext.eq_ignore_ascii_case("mp3") => FileFormat::Mp3,
+            _ => FileFormat::Wav,
+        }
+    }
+}
+
+/// Size of the ID3v2 tag some taggers put in front of FLAC streams, MP3 and even WAVE files, 0
+/// when there is none.
+fn id3v2_length(header: &[u8]) -> u64 {
+    if header.len() < 10 || &header[0..3] != b"ID3" {
+        return 0;
+    }
+    // The size is stored on 4 bytes of 7 bits and doesn't include the 10 byte header.
+    let size = header[6..10].iter().fold(0u64, |size, &b| size << 7 | (b & 0x7f) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+fn read_header(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(16);
+    file.take(16).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Maps two's complement integers of `bits` bits to `[-1, 1]`, the inverse of `Quantizer`.
+fn int_to_f32(bits: u16) -> impl Fn(i32) -> f32 {
+    let neg_range = 2f64.powi(bits as i32 - 1);
+    let pos_range = neg_range - 1.0;
+    move |s| {
+        if s < 0 {
+            (s as f64 / neg_range) as f32
+        } else {
+            (s as f64 / pos_range) as f32
+        }
+    }
+}
+
+fn int_bit_depth(bits: u16) -> BitDepth {
+    match bits {
+        0..=8 => BitDepth::Int8,
+        9..=16 => BitDepth::Int16,
+        17..=24 => BitDepth::Int24,
+        _ => BitDepth::Int32,
+    }
+}
+
+/// Frames read from a store at once when writing a file.
+const BLOCK_FRAMES: usize = 1 << 14;
+
+/// Calls `f` with consecutive blocks of interleaved frames that together make up `samples`.
+fn for_each_block<F: FnMut(&[f32]) -> io::Result<()>>(samples: &SampleStore, mut f: F) -> io::Result<()> {
+    let channels = samples.channels();
+    let mut plane = vec![0.0; BLOCK_FRAMES];
+    let mut block = vec![0.0; BLOCK_FRAMES * channels];
+    for start in (0..samples.frames()).step_by(BLOCK_FRAMES) {
+        let frames = (samples.frames() - start).min(BLOCK_FRAMES);
+        for c in 0..channels {
+            samples.read(c, start, &mut plane[..frames]);
+            for (i, &s) in plane[..frames].iter().enumerate() {
+                block[i * channels + c] = s;
+            }
+        }
+        f(&block[..frames * channels])?;
+    }
+    Ok(())
+}
+
+/// Each channel gets its own noise, correlated dither would image in the center.
+fn quantizers(bits: u16, channels: u16, options: &WriteOptions) -> Vec<Quantizer> {
+    (0..channels as u32)
+        .map(|c| Quantizer::new(bits, options.dither, options.noise_shaping, options.seed.wrapping_add(c)))
+        .collect()
+}
+
+pub fn samples_from_file<P: AsRef<Path>>(filename: P) -> Result<AudioFile, LoadError> {
+    samples_from_file_with_progress(filename, |_| true)
+}
+
+/// Counts the bytes read through it to report how much of the file has been decoded.
+struct ProgressReader<R, F> {
+    inner: R,
+    position: u64,
+    length: u64,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(f32) -> bool> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        if !(self.progress)(self.position as f32 / self.length.max(1) as f32) {
+            return Err(io::Error::other("Loading cancelled"));
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek, F> Seek for ProgressReader<R, F> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(position)?;
+        Ok(self.position)
+    }
+}
+
+/// Like `samples_from_file`, calling `progress` with the fraction of the file read so far as it
+/// goes. Loading fails with an I/O error as soon as `progress` returns `false`.
+pub fn samples_from_file_with_progress<P, F>(filename: P, progress: F) -> Result<AudioFile, LoadError>
+    where P: AsRef<Path>, F: FnMut(f32) -> bool
+{
+    let filename = filename.as_ref();
+    let mut file = File::open(filename)?;
+    let mut header = read_header(&mut file)?;
+    let offset = id3v2_length(&header);
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset))?;
+        header = read_header(&mut file)?;
+    }
+
+    let format = FileFormat::detect(&header)
+        .ok_or_else(|| LoadError::UnsupportedFormat("unrecognized file header".to_string()))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let length = file.metadata()?.len();
+    // Uncompressed samples are mapped rather than read, only the pages looked at get loaded.
+    let reader = BufReader::new(ProgressReader { inner: &file, position: offset, length, progress });
+    match format {
+        FileFormat::Wav | FileFormat::Wave64 => wav::load(reader, filename, Some(&file)),
+        FileFormat::Flac => flac::load(reader, filename),
+        FileFormat::Aiff => aiff::load(mapping::map(&file, offset, u64::MAX)?, filename),
+        FileFormat::Vorbis => vorbis::load(reader, filename),
+        FileFormat::Mp3 => mp3::load(reader, filename),
+    }
+}
+
+/// Reads headerless PCM data, whose layout has to come from the user.
+pub fn raw_samples_from_file<P: AsRef<Path>>(filename: P, layout: &RawLayout) -> Result<AudioFile, LoadError> {
+    let file = File::open(&filename)?;
+    raw::load(mapping::map(&file, 0, u64::MAX)?, filename.as_ref(), layout)
+}
+
+/// Encoding options used when writing a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteOptions {
+    pub bit_depth: BitDepth,
+    /// Only applies to integer bit depths.
+    pub dither: Dither,
+    pub noise_shaping: NoiseShaping,
+    /// Seed of the dither noise, the same seed always produces the same file.
+    pub seed: u32,
+}
+
+impl WriteOptions {
+    /// Plain rounding to `bit_depth`, which leaves samples loaded from a file of the same depth untouched.
+    pub fn new(bit_depth: BitDepth) -> Self {
+        Self {
+            bit_depth,
+            dither: Dither::None,
+            noise_shaping: NoiseShaping::None,
+            seed: 0,
+        }
+    }
+}
+
+/// Whether `a` and `b` name the same file, however they're written.
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Writes a file in the format matching the extension of `filename`.
+///
+/// The file is written next to `filename` then moved over it, files being saved may well be
+/// mapped by the store they are saved from. Headerless files are never written over the file
+/// they were imported from, it would get a header it isn't expected to have.
+pub fn samples_to_file<P: AsRef<Path>>(filename: P, file: &AudioFile, options: &WriteOptions) -> io::Result<()> {
+    let filename = filename.as_ref();
+    let format = FileFormat::from_extension(filename);
+    if let FileFormat::Vorbis | FileFormat::Mp3 = format {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Compressed formats can only be opened, save as WAV, FLAC or AIFF instead"));
+    }
+    if file.headerless && same_file(filename, &file.filename) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} is headerless PCM, save it under another name", filename.display())));
+    }
+
+    let mut temporary = filename.as_os_str().to_owned();
+    temporary.push(".part");
+    let output = BufWriter::new(File::create(&temporary)?);
+    let result = match format {
+        FileFormat::Wav => wav::save(output, file, options),
+        FileFormat::Wave64 => wav::write(output, file, options, wav::Container::Wave64),
+        FileFormat::Flac => flac::save(output, file, options),
+        FileFormat::Aiff => aiff::save(output, file, options),
+        FileFormat::Vorbis | FileFormat::Mp3 => unreachable!(),
     };
-    Ok((deinterleave(&samples, spec.channels as usize), spec.channels, spec.sample_rate))
+    match result {
+        Ok(()) => fs::rename(&temporary, filename),
+        Err(err) => {
+            fs::remove_file(&temporary).ok();
+            Err(err)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use waved_core::state::Marker;
+
+    fn test_file(samples: Vec<f32>, channels: u16, sample_rate: u32) -> AudioFile {
+        AudioFile::from_store(SampleStore::from_planar(samples, channels as usize), sample_rate)
+    }
+
+    fn planar(file: &AudioFile) -> Vec<f32> {
+        (0..file.channels as usize).flat_map(|c| file.samples.channel(c)).collect()
+    }
+
+    #[test]
+    fn test_wav_round_trip() {
+        let samples = vec![0.0, 0.5, -0.5, 1.0, -1.0, 0.25, 0.0, -0.25];
+        let file = test_file(samples.clone(), 2, 44100);
+        for &bit_depth in &[BitDepth::Int8, BitDepth::Int16, BitDepth::Int24, BitDepth::Int32, BitDepth::Float32] {
+            let path = std::env::temp_dir().join(format!("waved-round-trip-{}.wav", bit_depth.bits()));
+            samples_to_file(&path, &file, &WriteOptions::new(bit_depth)).unwrap();
+            let loaded = samples_from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!((loaded.channels, loaded.sample_rate, loaded.bit_depth), (2, 44100, bit_depth));
+            let tolerance = 1.0 / 2f32.powi(bit_depth.bits() as i32 - 2);
+            for (l, s) in planar(&loaded).iter().zip(&samples) {
+                assert!((l - s).abs() <= tolerance, "{} != {} ({:?})", l, s, bit_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wave64_round_trip() {
+        let file = test_file((0..1001).map(|i| (i as f32 * 0.03).sin()).collect(), 1, 22050);
+        let path = std::env::temp_dir().join("waved-round-trip.w64");
+        samples_to_file(&path, &file, &WriteOptions::new(BitDepth::Int24)).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let loaded = samples_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(FileFormat::detect(&bytes), Some(FileFormat::Wave64));
+        // Chunks are aligned to 8 bytes
+        assert_eq!(bytes.len() % 8, 0);
+        assert_eq!((loaded.channels, loaded.sample_rate, loaded.bit_depth), (1, 22050, BitDepth::Int24));
+        for (l, s) in planar(&loaded).iter().zip(&planar(&file)) {
+            assert!((l - s).abs() < 1e-6, "{} != {}", l, s);
+        }
+    }
+
+    #[test]
+    fn test_dithered_export_is_reproducible() {
+        let file = test_file((0..256).map(|i| (i as f32 * 0.1).sin() * 0.3).collect(), 2, 48000);
+        let options = WriteOptions {
+            dither: Dither::Tpdf,
+            noise_shaping: NoiseShaping::Wannamaker3,
+            seed: 42,
+            ..WriteOptions::new(BitDepth::Int16)
+        };
+
+        let export = |name: &str| {
+            let path = std::env::temp_dir().join(name);
+            samples_to_file(&path, &file, &options).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            bytes
+        };
+        assert_eq!(export("waved-dither-a.wav"), export("waved-dither-b.wav"));
+    }
+
+    #[test]
+    fn test_truncated_file_is_recovered() {
+        let path = std::env::temp_dir().join("waved-truncated.wav");
+        let samples: Vec<f32> = (0..200).map(|i| i as f32 / 200.0).collect();
+        samples_to_file(&path, &test_file(samples.clone(), 2, 44100), &WriteOptions::new(BitDepth::Int16)).unwrap();
+
+        // Cut the data chunk in the middle of a frame
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 101]).unwrap();
+
+        let result = samples_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(LoadError::Truncated { file, expected_frames }) => {
+                assert_eq!(expected_frames, 100);
+                assert_eq!(file.frames(), 74);
+                assert!((file.samples.get(1, 0) - samples[100]).abs() < 1e-4);
+            },
+            other => panic!("Unexpected result {:?}", other.map(|f| f.frames())),
+        }
+    }
+
+    #[test]
+    fn test_save_over_mapped_file() {
+        let path = std::env::temp_dir().join("waved-save-over.wav");
+        let samples: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+        samples_to_file(&path, &test_file(samples.clone(), 1, 44100), &WriteOptions::new(BitDepth::Int24)).unwrap();
+
+        // The loaded file still reads from the one it replaces
+        let loaded = samples_from_file(&path).unwrap();
+        samples_to_file(&path, &loaded, &WriteOptions::new(BitDepth::Int16)).unwrap();
+        let saved = samples_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.bit_depth, BitDepth::Int16);
+        for ((l, s), o) in planar(&loaded).iter().zip(&planar(&saved)).zip(&samples) {
+            assert!((l - o).abs() < 1e-6 && (s - o).abs() < 1e-4, "{} {} != {}", l, s, o);
+        }
+    }
+
+    #[test]
+    fn test_flac_round_trip() {
+        // Long enough for several frames, with a constant stretch and a full scale one.
+        let mut samples: Vec<f32> = (0..10000).map(|i| (i as f32 * 0.01).sin() * 0.8).collect();
+        samples.extend(vec![0.25; 3000]);
+        samples.extend((0..3000).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }));
+        let mut file = test_file(samples, 2, 96000);
+        file.tags = vec![("TITLE".to_string(), "Sine".to_string()), ("ARTIST".to_string(), "waved".to_string())];
+
+        for &bit_depth in &[BitDepth::Int8, BitDepth::Int16, BitDepth::Int24] {
+            let path = std::env::temp_dir().join(format!("waved-round-trip-{}.flac", bit_depth.bits()));
+            samples_to_file(&path, &file, &WriteOptions::new(bit_depth)).unwrap();
+            let loaded = samples_from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!((loaded.channels, loaded.sample_rate, loaded.bit_depth), (2, 96000, bit_depth));
+            assert_eq!(loaded.tags, file.tags);
+            assert_eq!(loaded.frames(), file.frames());
+            let tolerance = 1.0 / 2f32.powi(bit_depth.bits() as i32 - 2);
+            for (l, s) in planar(&loaded).iter().zip(&planar(&file)) {
+                assert!((l - s).abs() <= tolerance, "{} != {} ({:?})", l, s, bit_depth);
+            }
+        }
+
+        // Deeper samples are refused rather than truncated to 24 bits
+        let path = std::env::temp_dir().join("waved-round-trip-32.flac");
+        let result = samples_to_file(&path, &file, &WriteOptions::new(BitDepth::Float32));
+        assert_eq!(result.map_err(|err| err.kind()), Err(io::ErrorKind::InvalidInput));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_flac_with_bogus_length() {
+        let mut bytes = vec![];
+        flac::save(&mut bytes, &test_file(vec![0.5; 64], 8, 44100), &WriteOptions::new(BitDepth::Int16)).unwrap();
+        // Claim the largest total sample count STREAMINFO can hold
+        bytes[21] |= 0x0f;
+        bytes[22..26].copy_from_slice(&[0xff; 4]);
+
+        match flac::load(&bytes[..], Path::new("bogus.flac")) {
+            Err(LoadError::Truncated { file, expected_frames }) => {
+                assert_eq!(file.frames(), 8);
+                assert_eq!(expected_frames, (1 << 36) - 1);
+            },
+            _ => panic!("expected a truncated file"),
+        }
+    }
+
+    #[test]
+    fn test_aiff_round_trip() {
+        let mut file = test_file((0..2000).map(|i| (i as f32 * 0.02).sin() * 0.9).collect(), 2, 44100);
+        file.markers = vec![
+            Marker { position: 10, name: "Attack".to_string() },
+            Marker { position: 200, name: "Sustain".to_string() },
+        ];
+        file.loop_points = Some((200, 900));
+
+        for &bit_depth in &[BitDepth::Int8, BitDepth::Int16, BitDepth::Int24, BitDepth::Int32, BitDepth::Float32] {
+            let path = std::env::temp_dir().join(format!("waved-round-trip-{}.aiff", bit_depth.bits()));
+            samples_to_file(&path, &file, &WriteOptions::new(bit_depth)).unwrap();
+            let loaded = samples_from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!((loaded.channels, loaded.sample_rate, loaded.bit_depth), (2, 44100, bit_depth));
+            assert_eq!(loaded.loop_points, Some((200, 900)));
+            // The loop end had no marker of its own
+            assert_eq!(loaded.markers.len(), 3);
+            assert_eq!(loaded.markers[..2], file.markers[..]);
+            let tolerance = 1.0 / 2f32.powi(bit_depth.bits() as i32 - 2);
+            for (l, s) in planar(&loaded).iter().zip(&planar(&file)) {
+                assert!((l - s).abs() <= tolerance, "{} != {} ({:?})", l, s, bit_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_detected_from_content() {
+        // A FLAC stream hiding behind a .wav extension and an ID3 tag
+        let flac = std::env::temp_dir().join("waved-detect.flac");
+        samples_to_file(&flac, &test_file(vec![0.5; 64], 1, 44100), &WriteOptions::new(BitDepth::Int16)).unwrap();
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00\x05hello".to_vec();
+        bytes.extend(std::fs::read(&flac).unwrap());
+        let path = std::env::temp_dir().join("waved-detect.wav");
+        std::fs::write(&path, &bytes).unwrap();
+
+        let loaded = samples_from_file(&path);
+        std::fs::remove_file(&flac).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(FileFormat::detect(&bytes[15..]), Some(FileFormat::Flac));
+        assert_eq!(loaded.unwrap().frames(), 64);
+
+        // Same for a WAVE file, whose sample data gets mapped past the tag
+        let wav = std::env::temp_dir().join("waved-detect-id3.wav");
+        samples_to_file(&wav, &test_file(vec![0.5; 64], 1, 44100), &WriteOptions::new(BitDepth::Float32)).unwrap();
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00\x05hello".to_vec();
+        bytes.extend(std::fs::read(&wav).unwrap());
+        std::fs::write(&wav, &bytes).unwrap();
+        let loaded = samples_from_file(&wav);
+        std::fs::remove_file(&wav).unwrap();
+        assert_eq!(planar(&loaded.unwrap()), vec![0.5; 64]);
+    }
+
+    #[test]
+    fn test_headerless_file_is_not_overwritten() {
+        let path = std::env::temp_dir().join("waved-headerless.bin");
+        let bytes = [0x00, 0x40, 0x00, 0xc0];
+        std::fs::write(&path, bytes).unwrap();
+        let loaded = raw_samples_from_file(&path, &"i16le,1ch,8000".parse().unwrap()).unwrap();
+
+        let result = samples_to_file(&path, &loaded, &WriteOptions::new(loaded.bit_depth));
+        assert_eq!(result.map_err(|err| err.kind()), Err(io::ErrorKind::InvalidInput));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        let wav = std::env::temp_dir().join("waved-headerless.wav");
+        samples_to_file(&wav, &loaded, &WriteOptions::new(loaded.bit_depth)).unwrap();
+        assert_eq!(samples_from_file(&wav).unwrap().frames(), 2);
+        std::fs::remove_file(&wav).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file() {
+        match samples_from_file(std::env::temp_dir().join("waved-does-not-exist.wav")) {
+            Err(LoadError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            other => panic!("Unexpected result {:?}", other.map(|f| f.frames())),
+        }
+    }
 }
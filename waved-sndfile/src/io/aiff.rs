@@ -0,0 +1,357 @@
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::path::Path;
+
+use waved_core::state::{AudioFile, BitDepth, Marker};
+
+use super::mapping::Bytes;
+use super::raw::{Endianness, PcmSource, RawFormat, RawLayout};
+use super::{for_each_block, int_bit_depth, quantizers, LoadError, WriteOptions};
+
+/// Version of the AIFF-C specification, every AIFF-C file starts with it in an FVER chunk.
+const AIFC_VERSION: u32 = 0xa280_5140;
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    BigEndian,
+    /// `sowt`, written by Apple tools to avoid byte swapping on Intel machines.
+    LittleEndian,
+    Float32,
+    Float64,
+}
+
+struct Common {
+    channels: u16,
+    frames: u32,
+    bits: u16,
+    sample_rate: f64,
+    encoding: Encoding,
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[0..4].try_into().unwrap())
+}
+
+/// Decodes an 80-bit IEEE 754 extended precision number, which is how AIFF stores sample rates.
+fn read_extended(bytes: &[u8]) -> f64 {
+    let exponent = (read_u16(bytes) & 0x7fff) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 { -value } else { value }
+}
+
+fn write_extended(value: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if value > 0 {
+        // The mantissa has an explicit integer bit, normalize it to the top bit.
+        let shift = (value as u64).leading_zeros();
+        bytes[0..2].copy_from_slice(&(16383 + 63 - shift as u16).to_be_bytes());
+        bytes[2..10].copy_from_slice(&((value as u64) << shift).to_be_bytes());
+    }
+    bytes
+}
+
+/// Reads a Pascal string padded to an even length, returns it and the bytes that follow.
+fn read_pstring(bytes: &[u8]) -> Result<(String, &[u8]), LoadError> {
+    let length = *bytes.first().ok_or_else(|| LoadError::BadHeader("missing string".to_string()))? as usize;
+    let padded = (length + 2) & !1;
+    if bytes.len() < padded {
+        return Err(LoadError::BadHeader("string runs past its chunk".to_string()));
+    }
+    Ok((String::from_utf8_lossy(&bytes[1..length + 1]).into_owned(), &bytes[padded..]))
+}
+
+fn write_pstring(bytes: &mut Vec<u8>, text: &str) {
+    let text = &text.as_bytes()[..text.len().min(255)];
+    bytes.push(text.len() as u8);
+    bytes.extend_from_slice(text);
+    if text.len().is_multiple_of(2) {
+        bytes.push(0);
+    }
+}
+
+fn parse_common(body: &[u8], aifc: bool) -> Result<Common, LoadError> {
+    if body.len() < 18 || (aifc && body.len() < 22) {
+        return Err(LoadError::BadHeader("COMM chunk too short".to_string()));
+    }
+
+    let encoding = if aifc {
+        match &body[18..22] {
+            b"NONE" | b"twos" => Encoding::BigEndian,
+            b"sowt" => Encoding::LittleEndian,
+            b"fl32" | b"FL32" => Encoding::Float32,
+            b"fl64" | b"FL64" => Encoding::Float64,
+            other => return Err(LoadError::UnsupportedFormat(
+                format!("AIFF-C compression {}", String::from_utf8_lossy(other)))),
+        }
+    } else {
+        Encoding::BigEndian
+    };
+
+    let common = Common {
+        channels: read_u16(&body[0..2]),
+        frames: read_u32(&body[2..6]),
+        bits: read_u16(&body[6..8]),
+        sample_rate: read_extended(&body[8..18]),
+        encoding,
+    };
+    if common.channels == 0 {
+        return Err(LoadError::BadHeader("no channels".to_string()));
+    }
+    // Also catches the NaN and infinities an extended float can hold
+    if !(1.0..=u32::MAX as f64).contains(&common.sample_rate.round()) {
+        return Err(LoadError::BadHeader(format!("sample rate of {} Hz", common.sample_rate)));
+    }
+    if let Encoding::BigEndian | Encoding::LittleEndian = encoding {
+        if common.bits == 0 || common.bits > 32 {
+            return Err(LoadError::BadHeader(format!("{} bits per sample", common.bits)));
+        }
+    }
+    Ok(common)
+}
+
+fn parse_markers(body: &[u8]) -> Result<Vec<(u16, Marker)>, LoadError> {
+    if body.len() < 2 {
+        return Err(LoadError::BadHeader("MARK chunk too short".to_string()));
+    }
+    let mut rest = &body[2..];
+    let mut markers = vec![];
+    for _ in 0..read_u16(body) {
+        if rest.len() < 6 {
+            return Err(LoadError::BadHeader("MARK chunk too short".to_string()));
+        }
+        let (name, next) = read_pstring(&rest[6..])?;
+        markers.push((read_u16(rest), Marker { position: read_u32(&rest[2..6]) as usize, name }));
+        rest = next;
+    }
+    Ok(markers)
+}
+
+pub fn load(bytes: Bytes, filename: &Path) -> Result<AudioFile, LoadError> {
+    if bytes.len() < 12 {
+        return Err(LoadError::BadHeader("missing FORM header".to_string()));
+    }
+    let aifc = &bytes[8..12] == b"AIFC";
+
+    let mut common = None;
+    // Range of the sample data in `bytes`, decoded from there on demand
+    let mut sound = 0..0;
+    let mut markers = vec![];
+    let mut loop_ids = None;
+    let mut truncated = false;
+    let mut rest = &bytes[12..];
+    while rest.len() >= 8 {
+        let size = read_u32(&rest[4..8]) as usize;
+        let (id, data) = rest.split_at(8);
+        let body = if size <= data.len() {
+            // Chunks are padded to an even size
+            rest = &data[(size + size % 2).min(data.len())..];
+            &data[..size]
+        } else {
+            truncated = true;
+            rest = &[];
+            data
+        };
+
+        match &id[0..4] {
+            b"COMM" => common = Some(parse_common(body, aifc)?),
+            b"SSND" if body.len() >= 8 => {
+                let start = bytes.len() - data.len() + 8 + read_u32(body) as usize;
+                let end = bytes.len() - data.len() + body.len();
+                sound = start.min(end)..end;
+            },
+            b"MARK" => markers = parse_markers(body)?,
+            // Only the sustain loop is kept, the release loop has no equivalent in waved.
+            b"INST" if body.len() >= 20 && read_u16(&body[8..10]) != 0 => {
+                loop_ids = Some((read_u16(&body[10..12]), read_u16(&body[12..14])));
+            },
+            _ => {},
+        }
+    }
+
+    let common = common.ok_or_else(|| LoadError::BadHeader("missing COMM chunk".to_string()))?;
+    let format = match (common.encoding, (common.bits as usize).div_ceil(8)) {
+        (Encoding::Float32, _) => RawFormat::F32,
+        (Encoding::Float64, _) => RawFormat::F64,
+        // Sample points are left-aligned in whole bytes
+        (_, 1) => RawFormat::I8,
+        (_, 2) => RawFormat::I16,
+        (_, 3) => RawFormat::I24,
+        (_, _) => RawFormat::I32,
+    };
+    let layout = RawLayout {
+        format,
+        endianness: match common.encoding {
+            Encoding::LittleEndian => Endianness::Little,
+            _ => Endianness::Big,
+        },
+        channels: common.channels,
+        interleaved: true,
+        sample_rate: common.sample_rate.round() as u32,
+    };
+    let expected_frames = common.frames as usize;
+    let frame_size = common.channels as usize * format.size();
+    let frames = (sound.len() / frame_size).min(expected_frames);
+    let sound = sound.start..sound.start + frames * frame_size;
+
+    let find_marker = |id| markers.iter().find(|(i, _)| *i == id).map(|(_, m): &(u16, Marker)| m.position);
+    let loop_points = loop_ids
+        .and_then(|(start, end)| Some((find_marker(start)?, find_marker(end)?)))
+        .filter(|(start, end)| start < end);
+    let mut markers: Vec<Marker> = markers.into_iter().map(|(_, m)| m).collect();
+    markers.sort_by_key(|m| m.position);
+
+    let file = AudioFile {
+        filename: filename.to_path_buf(),
+        samples: PcmSource::new(bytes, sound, layout).into_store(),
+        channels: common.channels,
+        sample_rate: layout.sample_rate,
+        bit_depth: match common.encoding {
+            Encoding::Float32 | Encoding::Float64 => BitDepth::Float32,
+            Encoding::BigEndian | Encoding::LittleEndian => int_bit_depth(common.bits),
+        },
+        tags: vec![],
+        markers,
+        loop_points,
+        metadata: Default::default(),
+        headerless: false,
+    };
+
+    if truncated || frames < expected_frames {
+        Err(LoadError::Truncated { file: Box::new(file), expected_frames })
+    } else {
+        Ok(file)
+    }
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(body);
+    if !body.len().is_multiple_of(2) {
+        bytes.push(0);
+    }
+}
+
+/// Identifier of the marker at `position`, added under `name` when there is none yet.
+fn marker_id(markers: &mut Vec<Marker>, position: usize, name: &str) -> u16 {
+    let index = match markers.iter().position(|m| m.position == position) {
+        Some(index) => index,
+        None => {
+            markers.push(Marker { position, name: name.to_string() });
+            markers.len() - 1
+        },
+    };
+    // Identifiers must be positive
+    index as u16 + 1
+}
+
+/// Writes AIFF, or AIFF-C for floating point samples which plain AIFF can't hold.
+pub fn save<W: Write>(mut output: W, file: &AudioFile, options: &WriteOptions) -> io::Result<()> {
+    let too_large = |what| io::Error::new(io::ErrorKind::InvalidInput, format!("Too many {} for AIFF", what));
+    let frames: u32 = file.frames().try_into().map_err(|_| too_large("frames"))?;
+    let aifc = options.bit_depth == BitDepth::Float32;
+    let bits = options.bit_depth.bits();
+
+    let mut chunks = vec![];
+    if aifc {
+        write_chunk(&mut chunks, b"FVER", &AIFC_VERSION.to_be_bytes());
+    }
+
+    let mut common = vec![];
+    common.extend_from_slice(&file.channels.to_be_bytes());
+    common.extend_from_slice(&frames.to_be_bytes());
+    common.extend_from_slice(&bits.to_be_bytes());
+    common.extend_from_slice(&write_extended(file.sample_rate));
+    if aifc {
+        common.extend_from_slice(b"fl32");
+        write_pstring(&mut common, "32-bit floating point");
+    }
+    write_chunk(&mut chunks, b"COMM", &common);
+
+    // Loops point at markers, reuse the ones already there so that saving doesn't add new ones.
+    let mut markers = file.markers.clone();
+    let loop_ids = file.loop_points
+        .map(|(start, end)| (marker_id(&mut markers, start, "Loop start"), marker_id(&mut markers, end, "Loop end")));
+    if markers.len() > u16::MAX as usize - 1 {
+        return Err(too_large("markers"));
+    }
+    if !markers.is_empty() {
+        let mut body = vec![];
+        body.extend_from_slice(&(markers.len() as u16).to_be_bytes());
+        for (i, marker) in markers.iter().enumerate() {
+            body.extend_from_slice(&(i as u16 + 1).to_be_bytes());
+            body.extend_from_slice(&(marker.position.min(u32::MAX as usize) as u32).to_be_bytes());
+            write_pstring(&mut body, &marker.name);
+        }
+        write_chunk(&mut chunks, b"MARK", &body);
+    }
+    if let Some((start, end)) = loop_ids {
+        // Middle C over the whole key and velocity range, forward sustain loop and no release loop
+        let mut body = vec![60, 0, 0, 127, 1, 127, 0, 0];
+        for &value in &[1, start, end, 0, 0, 0] {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+        write_chunk(&mut chunks, b"INST", &body);
+    }
+
+    // The sample data is streamed after the other chunks, only its header goes in with them.
+    let size = bits as usize / 8;
+    let sound_size = 8 + file.frames() as u64 * file.channels as u64 * size as u64;
+    let padding = sound_size as usize % 2;
+    let form_size: u32 = (4 + chunks.len() as u64 + 8 + sound_size + padding as u64)
+        .try_into()
+        .map_err(|_| too_large("samples"))?;
+    output.write_all(b"FORM")?;
+    output.write_all(&form_size.to_be_bytes())?;
+    output.write_all(if aifc { b"AIFC" } else { b"AIFF" })?;
+    output.write_all(&chunks)?;
+    output.write_all(b"SSND")?;
+    output.write_all(&(sound_size as u32).to_be_bytes())?;
+    // No offset or block size
+    output.write_all(&[0; 8])?;
+
+    let mut quantizers = quantizers(bits, file.channels, options);
+    let mut buffer = vec![];
+    for_each_block(&file.samples, |block| {
+        buffer.clear();
+        for (i, &s) in block.iter().enumerate() {
+            if aifc {
+                buffer.extend_from_slice(&s.to_be_bytes());
+            } else {
+                let s = quantizers[i % file.channels as usize].quantize(s);
+                buffer.extend_from_slice(&s.to_be_bytes()[4 - size..]);
+            }
+        }
+        output.write_all(&buffer)
+    })?;
+    output.write_all(&[0][..padding])?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_sample_rate() {
+        let bytes = write_extended(44100);
+        assert_eq!(bytes, [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_extended(&bytes), 44100.0);
+        assert_eq!(read_extended(&write_extended(0)), 0.0);
+        assert_eq!(read_extended(&write_extended(192000)), 192000.0);
+    }
+
+    #[test]
+    fn test_zero_sample_rate() {
+        let mut body = vec![0, 1, 0, 0, 0, 4, 0, 16];
+        body.extend_from_slice(&write_extended(0));
+        assert!(matches!(parse_common(&body, false), Err(LoadError::BadHeader(_))));
+        body[8..18].copy_from_slice(&write_extended(44100));
+        assert!(parse_common(&body, false).is_ok());
+    }
+}
@@ -0,0 +1,318 @@
+use claxon::FlacReader;
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use waved_core::samples::SampleStore;
+use waved_core::state::{AudioFile, BitDepth};
+
+use super::{int_bit_depth, int_to_f32, quantizers, LoadError, WriteOptions};
+
+/// Frames per FLAC frame, the block size the reference encoder uses at its default settings.
+const BLOCK_SIZE: usize = 4096;
+
+impl From<claxon::Error> for LoadError {
+    fn from(err: claxon::Error) -> Self {
+        match err {
+            claxon::Error::IoError(err) => LoadError::Io(err),
+            claxon::Error::FormatError(reason) => LoadError::BadHeader(reason.to_string()),
+            claxon::Error::Unsupported(reason) => LoadError::UnsupportedFormat(reason.to_string()),
+        }
+    }
+}
+
+pub fn load<R: Read>(reader: R, filename: &Path) -> Result<AudioFile, LoadError> {
+    let mut reader = FlacReader::new(reader)?;
+    let info = reader.streaminfo();
+    let channels = info.channels as usize;
+    let bits = info.bits_per_sample as u16;
+    let tags = reader.tags()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    let convert = int_to_f32(bits);
+    // The count comes from the header, a corrupt one could claim terabytes. Only trust it as a guess.
+    let mut samples = Vec::with_capacity((info.samples.unwrap_or(0) * channels as u64).min(1 << 28) as usize);
+    let mut truncated = false;
+    for s in reader.samples() {
+        match s {
+            Ok(s) => samples.push(convert(s)),
+            // Claxon decodes whole frames, the partial frame at the end of a truncated stream is lost.
+            Err(claxon::Error::IoError(_)) => {
+                truncated = true;
+                break;
+            },
+            Err(err) => return Err(err.into()),
+        }
+    }
+    samples.truncate(samples.len() / channels * channels);
+
+    let frames = samples.len() / channels;
+    let expected_frames = info.samples.map_or(frames, |samples| samples as usize);
+    let file = AudioFile {
+        filename: filename.to_path_buf(),
+        samples: SampleStore::from_interleaved(samples, channels),
+        channels: channels as u16,
+        sample_rate: info.sample_rate,
+        bit_depth: int_bit_depth(bits),
+        tags,
+        markers: vec![],
+        loop_points: None,
+        metadata: Default::default(),
+        headerless: false,
+    };
+
+    if truncated || frames < expected_frames {
+        Err(LoadError::Truncated { file: Box::new(file), expected_frames })
+    } else {
+        Ok(file)
+    }
+}
+
+/// MSB first bit packer, FLAC frames aren't byte aligned until their footer.
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: vec![], acc: 0, bits: 0 }
+    }
+
+    /// Appends the `bits` low bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        self.acc = self.acc << bits | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    /// Writes a frame number with FLAC's extension of the UTF-8 encoding to 36 bits.
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        // A sequence of n bytes carries 5n + 1 bits
+        let mut length = 2;
+        while value >= 1 << (5 * length + 1) {
+            length += 1;
+        }
+        let prefix = (0xff00 >> length) as u64 & 0xff;
+        self.write(prefix | value >> (6 * (length - 1)), 8);
+        for i in (0..length - 1).rev() {
+            self.write(0x80 | (value >> (6 * i)) & 0x3f, 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ b, |crc, _| if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ (b as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+/// Residual of the fixed polynomial predictor of `order`, whose warmup is the first `order` samples.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let mut residual = samples.to_vec();
+    for _ in 0..order {
+        for i in (1..residual.len()).rev() {
+            residual[i] -= residual[i - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+fn zigzag(value: i64) -> u64 {
+    (value << 1 ^ value >> 63) as u64
+}
+
+/// Bits needed to rice code `residual` with parameter `k`.
+fn rice_cost(residual: &[i64], k: u32) -> u64 {
+    residual.iter().map(|&r| (zigzag(r) >> k) + 1 + k as u64).sum()
+}
+
+/// Rice parameter for a single partition. Only parameters around the one suited to the mean
+/// are tried, the cost is close to convex in `k`.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let mean = residual.iter().map(|&r| zigzag(r)).sum::<u64>() / residual.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(30);
+    // 31 is the escape code, which claxon doesn't implement.
+    (guess.saturating_sub(1)..=(guess + 1).min(30))
+        .map(|k| (k, rice_cost(residual, k)))
+        .min_by_key(|&(_, cost)| cost)
+        .unwrap()
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bits: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], bits);
+        return;
+    }
+
+    let verbatim_cost = samples.len() as u64 * bits as u64;
+    let best = (0..=4.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (k, cost) = rice_parameter(&residual);
+            (order, residual, k, cost + (order as u64) * bits as u64)
+        })
+        .min_by_key(|(_, _, _, cost)| *cost);
+
+    match best {
+        Some((order, residual, k, cost)) if cost < verbatim_cost => {
+            writer.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &s in &samples[..order] {
+                writer.write_signed(s, bits);
+            }
+            // Parameters above 14 need the 5 bit variant, a single partition covers the block.
+            if k <= 14 {
+                writer.write(0b00, 2);
+                writer.write(0, 4);
+                writer.write(k as u64, 4);
+            } else {
+                writer.write(0b01, 2);
+                writer.write(0, 4);
+                writer.write(k as u64, 5);
+            }
+            for &r in &residual {
+                let u = zigzag(r);
+                writer.write_unary(u >> k);
+                writer.write(u, k);
+            }
+        },
+        _ => {
+            writer.write(0b0000_0010, 8);
+            for &s in samples {
+                writer.write_signed(s, bits);
+            }
+        },
+    }
+}
+
+fn write_frame(writer: &mut BitWriter, number: u64, block: &[Vec<i64>], bits: u32) {
+    let start = writer.bytes.len();
+    let frames = block[0].len();
+    let bits_code = match bits {
+        8 => 0b001,
+        16 => 0b100,
+        _ => 0b110,
+    };
+
+    writer.write(0xfff8, 16);
+    // 16 bit block size stored after the frame number, sample rate taken from STREAMINFO
+    writer.write(0b0111_0000, 8);
+    writer.write(((block.len() as u64 - 1) << 4) | bits_code << 1, 8);
+    writer.write_utf8(number);
+    writer.write(frames as u64 - 1, 16);
+    let crc = crc8(&writer.bytes[start..]);
+    writer.write(crc as u64, 8);
+
+    for channel in block {
+        write_subframe(writer, channel, bits);
+    }
+    writer.align();
+    let crc = crc16(&writer.bytes[start..]);
+    writer.write(crc as u64, 16);
+}
+
+fn metadata_header(writer: &mut BitWriter, last: bool, kind: u64, length: usize) {
+    writer.write(last as u64, 1);
+    writer.write(kind, 7);
+    writer.write(length as u64, 24);
+}
+
+pub fn save<W: Write>(mut output: W, file: &AudioFile, options: &WriteOptions) -> io::Result<()> {
+    if file.channels == 0 || file.channels > 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "FLAC supports 1 to 8 channels"));
+    }
+    // 32 bit FLAC is too recent for most decoders, claxon included. Rather than quietly dropping
+    // to 24 bits, leave it to the user to pick a format that keeps the samples as they are.
+    let bits: u16 = match options.bit_depth {
+        BitDepth::Int8 => 8,
+        BitDepth::Int16 => 16,
+        BitDepth::Int24 => 24,
+        BitDepth::Int32 | BitDepth::Float32 => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "FLAC holds at most 24 bits per sample, save as WAV or AIFF to keep 32-bit samples")),
+    };
+
+    let mut writer = BitWriter::new();
+    writer.write(u32::from_be_bytes(*b"fLaC") as u64, 32);
+
+    metadata_header(&mut writer, false, 0, 34);
+    writer.write(BLOCK_SIZE as u64, 16);
+    writer.write(BLOCK_SIZE as u64, 16);
+    // Unknown frame sizes and MD5 signature
+    writer.write(0, 24);
+    writer.write(0, 24);
+    writer.write(file.sample_rate as u64, 20);
+    writer.write(file.channels as u64 - 1, 3);
+    writer.write(bits as u64 - 1, 5);
+    let frames = file.frames() as u64;
+    writer.write(frames >> 32, 4);
+    writer.write(frames, 32);
+    for _ in 0..4 {
+        writer.write(0, 32);
+    }
+
+    let vendor = b"waved";
+    let comments: Vec<String> = file.tags.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let length = 8 + vendor.len() + comments.iter().map(|c| 4 + c.len()).sum::<usize>();
+    metadata_header(&mut writer, true, 4, length);
+    writer.bytes.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    writer.bytes.extend_from_slice(vendor);
+    writer.bytes.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in &comments {
+        writer.bytes.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        writer.bytes.extend_from_slice(comment.as_bytes());
+    }
+    output.write_all(&writer.bytes)?;
+
+    let mut quantizers = quantizers(bits, file.channels, options);
+    let mut plane = vec![0.0; BLOCK_SIZE];
+    for (number, start) in (0..file.frames()).step_by(BLOCK_SIZE).enumerate() {
+        let plane = &mut plane[..BLOCK_SIZE.min(file.frames() - start)];
+        let block: Vec<Vec<i64>> = quantizers.iter_mut()
+            .enumerate()
+            .map(|(c, quantizer)| {
+                file.samples.read(c, start, plane);
+                plane.iter().map(|&s| quantizer.quantize(s) as i64).collect()
+            })
+            .collect();
+
+        let mut writer = BitWriter::new();
+        write_frame(&mut writer, number as u64, &block, bits as u32);
+        output.write_all(&writer.bytes)?;
+    }
+    output.flush()
+}
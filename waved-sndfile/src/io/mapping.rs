@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Deref;
+
+/// Bytes of a file, either read into memory or mapped so that the system pages them in on
+/// demand.
+pub enum Bytes {
+    Owned(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(Mapping),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytes::Owned(bytes) => bytes,
+            #[cfg(unix)]
+            Bytes::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+/// Read-only view of part of a file. Writing to the file while it's mapped changes what the
+/// view sees, which is why files are saved by replacing them.
+#[cfg(unix)]
+pub struct Mapping {
+    address: *mut libc::c_void,
+    /// Length of the whole mapping, which starts on a page boundary.
+    mapped: usize,
+    /// Part of the mapping that was asked for.
+    offset: usize,
+    length: usize,
+}
+
+// The mapping is read-only and owned by this struct alone.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+#[cfg(unix)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(file: &File, offset: u64, length: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let start = offset - offset % page;
+        let mapped = length + (offset - start) as usize;
+        let address = unsafe {
+            libc::mmap(std::ptr::null_mut(), mapped, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), start as libc::off_t)
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { address, mapped, offset: (offset - start) as usize, length })
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts((self.address as *const u8).add(self.offset), self.length) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.address, self.mapped);
+        }
+    }
+}
+
+/// Maps `length` bytes of `file` from `offset`, falling back to reading them where mapping
+/// isn't possible. Fewer bytes come back when the file is shorter than that.
+pub fn map(file: &File, offset: u64, length: u64) -> io::Result<Bytes> {
+    // Touching a mapped page past the end of the file is a bus error
+    let length = length.min(file.metadata()?.len().saturating_sub(offset)) as usize;
+    #[cfg(unix)]
+    {
+        if length > 0 {
+            if let Ok(mapping) = Mapping::new(file, offset, length) {
+                return Ok(Bytes::Mapped(mapping));
+            }
+        }
+    }
+
+    let mut file = file;
+    let mut bytes = Vec::with_capacity(length);
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(length as u64).read_to_end(&mut bytes)?;
+    Ok(Bytes::Owned(bytes))
+}
@@ -0,0 +1,192 @@
+use minimp3::Decoder;
+
+use std::io::Read;
+use std::path::Path;
+
+use waved_core::samples::SampleStore;
+use waved_core::state::{AudioFile, BitDepth};
+
+use super::{int_to_f32, LoadError};
+
+/// Frames the synthesis filter bank of minimp3 lags behind its input. Like mpg123 it is one more
+/// than the 528 of the reference decoder, which LAME's delay field doesn't include.
+const DECODER_DELAY: usize = 529;
+
+/// Bytes searched for the first frame and its tag, frames are never more than a few KiB long.
+const TAG_SEARCH_BYTES: u64 = 1 << 16;
+
+/// Xing or Info tag found in place of the audio of the first frame, with LAME's extension.
+#[derive(Debug, PartialEq)]
+struct GaplessInfo {
+    samples_per_frame: usize,
+    /// Audio frames in the stream, not counting the one holding the tag.
+    frames: Option<usize>,
+    /// Silence added by the encoder at the start and the end of the stream.
+    delay: usize,
+    padding: usize,
+}
+
+impl GaplessInfo {
+    /// Decoded frames to drop at the start: the tag's own silent frame, then both delays.
+    fn skip(&self) -> usize {
+        let delay = if self.delay > 0 || self.padding > 0 { self.delay + DECODER_DELAY } else { 0 };
+        self.samples_per_frame + delay
+    }
+
+    fn length(&self) -> Option<usize> {
+        self.frames.map(|frames| (frames * self.samples_per_frame).saturating_sub(self.delay + self.padding))
+    }
+}
+
+/// Offset of the first MPEG audio layer III frame header.
+fn find_frame(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|w| w[0] == 0xff && w[1] & 0xe0 == 0xe0 && (w[1] >> 1) & 3 == 1)
+}
+
+/// Reads the Xing/Info tag and LAME extension from the first frame, if the encoder wrote one.
+fn read_gapless_info(frame: &[u8]) -> Option<GaplessInfo> {
+    if frame.len() < 4 {
+        return None;
+    }
+    let mpeg1 = (frame[1] >> 3) & 3 == 3;
+    let mono = frame[3] >> 6 == 3;
+    let crc = if frame[1] & 1 == 0 { 2 } else { 0 };
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+
+    let tag = frame.get(4 + crc + side_info..)?;
+    if tag.len() < 8 || (&tag[0..4] != b"Xing" && &tag[0..4] != b"Info") {
+        return None;
+    }
+    let read_u32 = |bytes: &[u8]| bytes.get(0..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let flags = read_u32(&tag[4..])?;
+
+    let mut offset = 8;
+    let mut frames = None;
+    if flags & 1 != 0 {
+        frames = Some(read_u32(&tag[offset..])? as usize);
+        offset += 4;
+    }
+    // Byte count, table of contents and quality indicator
+    for &(flag, size) in &[(2, 4), (4, 100), (8, 4)] {
+        if flags & flag != 0 {
+            offset += size;
+        }
+    }
+
+    // The extension starts with a 9 byte encoder version, "LAME3.100" or "Lavc58.91" for instance.
+    let (delay, padding) = match tag.get(offset + 21..offset + 24) {
+        Some(d) if tag[offset..].len() >= 36 && tag[offset..offset + 4].iter().all(u8::is_ascii_alphabetic) => (
+            ((d[0] as usize) << 4) | (d[1] as usize >> 4),
+            ((d[1] as usize & 0xf) << 8) | d[2] as usize,
+        ),
+        _ => (0, 0),
+    };
+
+    Some(GaplessInfo {
+        samples_per_frame: if mpeg1 { 1152 } else { 576 },
+        frames,
+        delay,
+        padding,
+    })
+}
+
+pub fn load<R: Read>(mut reader: R, filename: &Path) -> Result<AudioFile, LoadError> {
+    // Only the start is held on to for the tag, the rest is decoded as it is read.
+    let mut head = vec![];
+    reader.by_ref().take(TAG_SEARCH_BYTES).read_to_end(&mut head)?;
+    let gapless = find_frame(&head).and_then(|offset| read_gapless_info(&head[offset..]));
+
+    let mut decoder = Decoder::new(head.as_slice().chain(reader));
+    let convert = int_to_f32(16);
+    let mut samples = vec![];
+    let mut channels = 0;
+    let mut sample_rate = 0;
+    loop {
+        match decoder.next_frame() {
+            Ok(frame) => {
+                if channels == 0 {
+                    channels = frame.channels;
+                    sample_rate = frame.sample_rate as u32;
+                } else if frame.channels != channels {
+                    return Err(LoadError::UnsupportedFormat("channel count changes mid-stream".to_string()));
+                }
+                samples.extend(frame.data.iter().map(|&s| convert(s as i32)));
+            },
+            Err(minimp3::Error::SkippedData) => {},
+            // A partial frame at the end is junk or the result of a cut, only the tag can tell.
+            Err(minimp3::Error::Eof) | Err(minimp3::Error::InsufficientData) => break,
+            Err(minimp3::Error::Io(err)) => return Err(err.into()),
+        }
+    }
+    if channels == 0 {
+        return Err(LoadError::BadHeader("no MPEG audio frames".to_string()));
+    }
+
+    let decoded = samples.len() / channels;
+    let (first, mut last) = match &gapless {
+        Some(info) => {
+            // The end of the stream comes out late by the decoder delay, minimp3 doesn't flush it.
+            let last = (decoded + DECODER_DELAY).saturating_sub(info.padding).min(decoded);
+            (info.skip().min(decoded), last)
+        },
+        None => (0, decoded),
+    };
+    let expected_frames = gapless.as_ref().and_then(GaplessInfo::length);
+    if let Some(length) = expected_frames {
+        last = last.min(first + length);
+    }
+
+    samples.truncate(last.max(first) * channels);
+    samples.drain(..first * channels);
+    let file = AudioFile {
+        filename: filename.to_path_buf(),
+        samples: SampleStore::from_interleaved(samples, channels),
+        channels: channels as u16,
+        sample_rate,
+        // What the decoder outputs, MP3 itself has no bit depth.
+        bit_depth: BitDepth::Int16,
+        tags: vec![],
+        markers: vec![],
+        loop_points: None,
+        metadata: Default::default(),
+        headerless: false,
+    };
+
+    match expected_frames {
+        Some(expected_frames) if file.frames() < expected_frames =>
+            Err(LoadError::Truncated { file: Box::new(file), expected_frames }),
+        _ => Ok(file),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lame_tag() {
+        // MPEG-1 layer III, 128 kbps, 44100 Hz, joint stereo, without CRC
+        let mut frame = vec![0; 417];
+        frame[0..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x64]);
+        frame[36..40].copy_from_slice(b"Info");
+        frame[40..44].copy_from_slice(&1u32.to_be_bytes());
+        frame[44..48].copy_from_slice(&100u32.to_be_bytes());
+        frame[48..57].copy_from_slice(b"LAME3.100");
+        // 576 frames of delay and 1200 of padding packed on 12 bits each
+        frame[69..72].copy_from_slice(&[0x24, 0x04, 0xb0]);
+
+        let mut bytes = vec![0x12, 0x34];
+        bytes.extend(frame);
+        let offset = find_frame(&bytes).unwrap();
+        assert_eq!(offset, 2);
+
+        let info = read_gapless_info(&bytes[offset..]).unwrap();
+        assert_eq!(info, GaplessInfo { samples_per_frame: 1152, frames: Some(100), delay: 576, padding: 1200 });
+        assert_eq!(info.skip(), 1152 + 576 + 529);
+        assert_eq!(info.length(), Some(115200 - 576 - 1200));
+    }
+}
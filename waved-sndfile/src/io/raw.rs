@@ -0,0 +1,273 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use waved_core::samples::{PageSource, SampleStore};
+use waved_core::state::{AudioFile, BitDepth};
+
+use super::mapping::Bytes;
+use super::{int_to_f32, LoadError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawFormat {
+    /// Unsigned, silence sits at 128.
+    U8,
+    I8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl RawFormat {
+    pub(super) fn size(self) -> usize {
+        match self {
+            RawFormat::U8 | RawFormat::I8 => 1,
+            RawFormat::I16 => 2,
+            RawFormat::I24 => 3,
+            RawFormat::I32 | RawFormat::F32 => 4,
+            RawFormat::F64 => 8,
+        }
+    }
+
+    fn bit_depth(self) -> BitDepth {
+        match self {
+            RawFormat::U8 | RawFormat::I8 => BitDepth::Int8,
+            RawFormat::I16 => BitDepth::Int16,
+            RawFormat::I24 => BitDepth::Int24,
+            RawFormat::I32 => BitDepth::Int32,
+            RawFormat::F32 | RawFormat::F64 => BitDepth::Float32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Everything needed to make sense of headerless PCM data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawLayout {
+    pub format: RawFormat,
+    pub endianness: Endianness,
+    pub channels: u16,
+    /// Frames are stored one after the other when set, otherwise each channel is stored whole
+    /// before the next one.
+    pub interleaved: bool,
+    pub sample_rate: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RawLayoutError {
+    UnknownField(String),
+    InvalidChannels(String),
+    MissingFormat,
+    MissingSampleRate,
+}
+
+impl fmt::Display for RawLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawLayoutError::UnknownField(field) => write!(f, "Unknown raw layout field '{}'", field),
+            RawLayoutError::InvalidChannels(field) => write!(f, "Invalid channel count '{}'", field),
+            RawLayoutError::MissingFormat => write!(f, "Missing sample format, e.g. i16le"),
+            RawLayoutError::MissingSampleRate => write!(f, "Missing sample rate, e.g. 48000"),
+        }
+    }
+}
+
+impl std::error::Error for RawLayoutError {}
+
+/// Parses comma separated fields in any order, like `i16le,2ch,48000` or `f32be,48000,6ch,planar`.
+///
+/// The format is one of `u8`, `i8`, `i16`, `i24`, `i32`, `f32` and `f64`, optionally followed by `le`
+/// or `be`, little endian being the default. A single interleaved channel is assumed when those
+/// fields are left out.
+impl FromStr for RawLayout {
+    type Err = RawLayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = None;
+        let mut endianness = Endianness::default();
+        let mut channels = 1;
+        let mut interleaved = true;
+        let mut sample_rate = None;
+
+        for field in s.split(',').map(str::trim).map(str::to_ascii_lowercase) {
+            let (name, order) = if let Some(name) = field.strip_suffix("le") {
+                (name, Endianness::Little)
+            } else if let Some(name) = field.strip_suffix("be") {
+                (name, Endianness::Big)
+            } else {
+                (&field[..], Endianness::default())
+            };
+            let parsed_format = match name {
+                "u8" => Some(RawFormat::U8),
+                "i8" => Some(RawFormat::I8),
+                "i16" => Some(RawFormat::I16),
+                "i24" => Some(RawFormat::I24),
+                "i32" => Some(RawFormat::I32),
+                "f32" => Some(RawFormat::F32),
+                "f64" => Some(RawFormat::F64),
+                _ => None,
+            };
+
+            if let Some(parsed_format) = parsed_format {
+                format = Some(parsed_format);
+                endianness = order;
+            } else if let Some(count) = field.strip_suffix("ch") {
+                channels = count.parse().ok()
+                    .filter(|&c| c > 0)
+                    .ok_or_else(|| RawLayoutError::InvalidChannels(field.clone()))?;
+            } else if field == "planar" || field == "interleaved" {
+                interleaved = field == "interleaved";
+            } else if let Ok(rate) = field.trim_end_matches("hz").parse() {
+                sample_rate = Some(rate);
+            } else {
+                return Err(RawLayoutError::UnknownField(field));
+            }
+        }
+
+        Ok(RawLayout {
+            format: format.ok_or(RawLayoutError::MissingFormat)?,
+            endianness,
+            channels,
+            interleaved,
+            sample_rate: sample_rate.filter(|&r| r > 0).ok_or(RawLayoutError::MissingSampleRate)?,
+        })
+    }
+}
+
+fn decode(sample: &[u8], format: RawFormat) -> f32 {
+    match format {
+        RawFormat::U8 => int_to_f32(8)(sample[0] as i32 - 128),
+        RawFormat::I8 => int_to_f32(8)(sample[0] as i8 as i32),
+        RawFormat::I16 => int_to_f32(16)(i16::from_be_bytes([sample[0], sample[1]]) as i32),
+        // Sign extend from the top of an i32
+        RawFormat::I24 => int_to_f32(24)(i32::from_be_bytes([sample[0], sample[1], sample[2], 0]) >> 8),
+        RawFormat::I32 => int_to_f32(32)(i32::from_be_bytes(sample.try_into().unwrap())),
+        RawFormat::F32 => f32::from_be_bytes(sample.try_into().unwrap()),
+        RawFormat::F64 => f64::from_be_bytes(sample.try_into().unwrap()) as f32,
+    }
+}
+
+/// PCM samples decoded on demand from bytes laid out as described by a `RawLayout`. This is what
+/// every uncompressed format boils down to once its header is parsed.
+pub struct PcmSource {
+    bytes: Bytes,
+    offset: usize,
+    layout: RawLayout,
+    frames: usize,
+}
+
+impl PcmSource {
+    /// Reads the samples in `range` of `bytes`. A partial frame at the end, or the incomplete
+    /// last channel of planar data, is left out.
+    pub fn new(bytes: Bytes, range: Range<usize>, layout: RawLayout) -> Self {
+        let frames = range.len() / (layout.format.size() * layout.channels as usize);
+        Self { bytes, offset: range.start, layout, frames }
+    }
+
+    pub fn into_store(self) -> SampleStore {
+        let channels = self.layout.channels as usize;
+        SampleStore::new(Arc::new(self), channels)
+    }
+}
+
+impl PageSource for PcmSource {
+    fn frames(&self) -> usize {
+        self.frames
+    }
+
+    fn read(&self, channel: usize, start: usize, out: &mut [f32]) {
+        let size = self.layout.format.size();
+        let channels = self.layout.channels as usize;
+        let (first, stride) = if self.layout.interleaved {
+            (start * channels + channel, channels)
+        } else {
+            (channel * self.frames + start, 1)
+        };
+
+        let mut sample = [0; 8];
+        let sample = &mut sample[..size];
+        for (i, s) in out.iter_mut().enumerate() {
+            // Decode everything as big endian
+            let at = self.offset + (first + i * stride) * size;
+            sample.copy_from_slice(&self.bytes[at..at + size]);
+            if self.layout.endianness == Endianness::Little {
+                sample.reverse();
+            }
+            *s = decode(sample, self.layout.format);
+        }
+    }
+}
+
+/// Reads headerless PCM, there's no header to tell how long the data was meant to be.
+pub fn load(bytes: Bytes, filename: &Path, layout: &RawLayout) -> Result<AudioFile, LoadError> {
+    let length = bytes.len();
+    Ok(AudioFile {
+        filename: filename.to_path_buf(),
+        samples: PcmSource::new(bytes, 0..length, *layout).into_store(),
+        channels: layout.channels,
+        sample_rate: layout.sample_rate,
+        bit_depth: layout.format.bit_depth(),
+        tags: vec![],
+        markers: vec![],
+        loop_points: None,
+        metadata: Default::default(),
+        headerless: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_layout() {
+        assert_eq!("i16le,2ch,48000".parse(), Ok(RawLayout {
+            format: RawFormat::I16,
+            endianness: Endianness::Little,
+            channels: 2,
+            interleaved: true,
+            sample_rate: 48000,
+        }));
+        assert_eq!("48000Hz, F32BE, 6ch, planar".parse(), Ok(RawLayout {
+            format: RawFormat::F32,
+            endianness: Endianness::Big,
+            channels: 6,
+            interleaved: false,
+            sample_rate: 48000,
+        }));
+        assert_eq!("u8,8000".parse::<RawLayout>().map(|l| l.channels), Ok(1));
+        assert_eq!("i16,0ch,8000".parse::<RawLayout>(), Err(RawLayoutError::InvalidChannels("0ch".to_string())));
+        assert_eq!("i16,2ch".parse::<RawLayout>(), Err(RawLayoutError::MissingSampleRate));
+        assert_eq!("i12,8000".parse::<RawLayout>(), Err(RawLayoutError::UnknownField("i12".to_string())));
+    }
+
+    #[test]
+    fn test_load_raw() {
+        let layout = "i24be,2ch,44100".parse().unwrap();
+        let bytes = [0x7f, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x00, 0x00, 0xff];
+        let file = load(Bytes::Owned(bytes.to_vec()), Path::new("dump.bin"), &layout).unwrap();
+        assert_eq!(file.frames(), 2);
+        assert_eq!(file.samples.channel(0), [1.0, 0.0]);
+        assert_eq!(file.samples.channel(1), [-1.0, -0.5]);
+
+        let layout = RawLayout { interleaved: false, ..layout };
+        let file = load(Bytes::Owned(bytes.to_vec()), Path::new("dump.bin"), &layout).unwrap();
+        assert_eq!(file.samples.channel(0), [1.0, -1.0]);
+        assert_eq!(file.samples.channel(1), [0.0, -0.5]);
+
+        let layout = "u8,8000".parse().unwrap();
+        let file = load(Bytes::Owned(vec![0x80, 0xff, 0x00]), Path::new("dump.bin"), &layout).unwrap();
+        assert_eq!(file.samples.channel(0), [0.0, 1.0, -1.0]);
+    }
+}
@@ -0,0 +1,114 @@
+use lewton::inside_ogg::OggStreamReader;
+use lewton::header::HeaderReadError;
+use lewton::{OggReadError, VorbisError};
+
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use waved_core::samples::SampleStore;
+use waved_core::state::{AudioFile, BitDepth};
+
+use super::LoadError;
+
+impl From<VorbisError> for LoadError {
+    fn from(err: VorbisError) -> Self {
+        match err {
+            VorbisError::OggError(OggReadError::ReadError(err)) => LoadError::Io(err),
+            VorbisError::BadHeader(HeaderReadError::NotVorbisHeader) =>
+                LoadError::UnsupportedFormat("Ogg stream isn't Vorbis".to_string()),
+            err => LoadError::BadHeader(err.to_string()),
+        }
+    }
+}
+
+/// First and past the end decoded frames that belong to the stream according to its granule
+/// positions.
+///
+/// `start` is the granule position of the first decoded frame, negative when the encoder primed
+/// the stream with audio that has to be dropped. `end` is the granule position of the last page.
+fn granule_range(decoded: usize, start: i64, end: u64) -> (usize, usize) {
+    let skip = (-start).max(0) as usize;
+    let length = (end as i64 - start).max(0) as usize;
+    (skip.min(decoded), length.min(decoded))
+}
+
+pub fn load<R: Read + Seek>(reader: R, filename: &Path) -> Result<AudioFile, LoadError> {
+    let mut reader = OggStreamReader::new(reader)?;
+    let channels = reader.ident_hdr.audio_channels as usize;
+    if channels == 0 {
+        return Err(LoadError::BadHeader("no channels".to_string()));
+    }
+
+    let mut planes = vec![vec![]; channels];
+    // Granule of the first frame, known once the first page ending on a packet has been read.
+    let mut start = None;
+    let mut later_pages = false;
+    let mut truncated = false;
+    loop {
+        match reader.read_dec_packet_generic::<Vec<Vec<f32>>>() {
+            Ok(Some(packet)) => {
+                for (plane, samples) in planes.iter_mut().zip(packet) {
+                    plane.extend(samples);
+                }
+                match start {
+                    Some(_) => later_pages = true,
+                    None => start = reader.get_last_absgp().map(|granule| granule as i64 - planes[0].len() as i64),
+                }
+            },
+            Ok(None) => break,
+            // Ogg can't tell how long the stream was meant to be, keep what decoded.
+            Err(VorbisError::OggError(OggReadError::ReadError(_))) => {
+                truncated = true;
+                break;
+            },
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let decoded = planes[0].len();
+    let (first, last) = match (start, reader.get_last_absgp()) {
+        // On a single page stream, a short granule trims the end rather than the start.
+        (Some(_), Some(end)) if !later_pages => granule_range(decoded, 0, end),
+        (Some(start), Some(end)) if !truncated => granule_range(decoded, start, end),
+        (Some(start), _) => (granule_range(decoded, start, 0).0, decoded),
+        _ => (0, decoded),
+    };
+    let samples = planes.iter()
+        .flat_map(|plane| plane[first..last.max(first)].iter().copied())
+        .collect();
+
+    let file = AudioFile {
+        filename: filename.to_path_buf(),
+        samples: SampleStore::from_planar(samples, channels),
+        channels: channels as u16,
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        bit_depth: BitDepth::Float32,
+        tags: reader.comment_hdr.comment_list.clone(),
+        markers: vec![],
+        loop_points: None,
+        metadata: Default::default(),
+        headerless: false,
+    };
+
+    if truncated {
+        let expected_frames = file.frames();
+        Err(LoadError::Truncated { file: Box::new(file), expected_frames })
+    } else {
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_granule_range() {
+        // Stream starting at zero, the last page cuts the final packet short
+        assert_eq!(granule_range(5000, 0, 4410), (0, 4410));
+        // Encoder priming, the first 576 frames come before the start of the stream
+        assert_eq!(granule_range(5000, -576, 4410), (576, 4986));
+        // Stream cut out of a longer one, nothing to drop at the start
+        assert_eq!(granule_range(5000, 1000, 6000), (0, 5000));
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::Path;
+
+use waved_core::state::{AudioFile, BitDepth, BroadcastExtension, Chunk, Marker, Metadata};
+
+use super::mapping::{self, Bytes};
+use super::raw::{Endianness, PcmSource, RawFormat, RawLayout};
+use super::{for_each_block, int_bit_depth, quantizers, LoadError, WriteOptions};
+
+/// Wave64 identifies chunks with GUIDs. Chunks that also exist in RIFF start with the same four
+/// characters, followed by a fixed suffix. `riff` and `list` each have a suffix of their own.
+const W64_RIFF_SUFFIX: [u8; 12] = [0x2e, 0x91, 0xcf, 0x11, 0xa5, 0xd6, 0x28, 0xdb, 0x04, 0xc1, 0x00, 0x00];
+const W64_LIST_SUFFIX: [u8; 12] = [0x2f, 0x91, 0xcf, 0x11, 0xa5, 0xd6, 0x28, 0xdb, 0x04, 0xc1, 0x00, 0x00];
+const W64_CHUNK_SUFFIX: [u8; 12] = [0xf3, 0xac, 0xd3, 0x11, 0x8c, 0xd1, 0x00, 0xc0, 0x4f, 0x8e, 0xdb, 0x8a];
+
+/// Tail of the sub-format GUID of WAVE_FORMAT_EXTENSIBLE, the format tag goes in front of it.
+const SUBFORMAT_SUFFIX: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Size of the `bext` chunk before the coding history.
+const BEXT_SIZE: usize = 602;
+
+/// LIST-INFO items that have a name other formats agree on, the others are tagged with their id.
+const INFO_TAGS: [(&[u8; 4], &str); 10] = [
+    (b"INAM", "TITLE"),
+    (b"IART", "ARTIST"),
+    (b"IPRD", "ALBUM"),
+    (b"ITRK", "TRACKNUMBER"),
+    (b"ICRD", "DATE"),
+    (b"IGNR", "GENRE"),
+    (b"ICMT", "COMMENT"),
+    (b"ICOP", "COPYRIGHT"),
+    (b"IENG", "ENGINEER"),
+    (b"ISFT", "ENCODER"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+    Riff,
+    /// RF64 or BW64, RIFF with 64 bit sizes stored in a `ds64` chunk.
+    Rf64,
+    /// Sony Wave64, 64 bit sizes everywhere and GUIDs instead of chunk names.
+    Wave64,
+}
+
+impl Container {
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        match header.get(0..4)? {
+            b"RIFF" if header.get(8..12)? == b"WAVE" => Some(Container::Riff),
+            b"RF64" | b"BW64" if header.get(8..12)? == b"WAVE" => Some(Container::Rf64),
+            b"riff" if header.get(4..16)? == W64_RIFF_SUFFIX => Some(Container::Wave64),
+            _ => None,
+        }
+    }
+
+    /// Size of the file header, up to and including the `WAVE` form type.
+    fn header_size(self) -> usize {
+        match self {
+            Container::Riff | Container::Rf64 => 12,
+            Container::Wave64 => 40,
+        }
+    }
+
+    fn chunk_header_size(self) -> u64 {
+        match self {
+            Container::Riff | Container::Rf64 => 8,
+            Container::Wave64 => 24,
+        }
+    }
+
+    /// Bytes of padding after a chunk body of `size` bytes.
+    fn padding(self, size: u64) -> u64 {
+        match self {
+            Container::Riff | Container::Rf64 => size % 2,
+            // Wave64 sizes include the header, which is already aligned
+            Container::Wave64 => (8 - size % 8) % 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SampleFormat {
+    Int,
+    Float,
+}
+
+struct Format {
+    sample_format: SampleFormat,
+    channels: u16,
+    sample_rate: u32,
+    /// Bits each sample takes in the file.
+    container_bits: u16,
+    /// Bits actually used by the samples, which may be less than the container.
+    valid_bits: u16,
+}
+
+impl Format {
+    fn sample_size(&self) -> usize {
+        self.container_bits as usize / 8
+    }
+
+    fn block_align(&self) -> usize {
+        self.sample_size() * self.channels as usize
+    }
+
+    fn layout(&self) -> RawLayout {
+        RawLayout {
+            format: match (self.sample_format, self.container_bits) {
+                // 8 bit WAV is the only unsigned one
+                (SampleFormat::Int, 8) => RawFormat::U8,
+                (SampleFormat::Int, 16) => RawFormat::I16,
+                (SampleFormat::Int, 24) => RawFormat::I24,
+                (SampleFormat::Int, _) => RawFormat::I32,
+                (SampleFormat::Float, 32) => RawFormat::F32,
+                (SampleFormat::Float, _) => RawFormat::F64,
+            },
+            endianness: Endianness::Little,
+            channels: self.channels,
+            interleaved: true,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[0..4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[0..8].try_into().unwrap())
+}
+
+fn parse_format(body: &[u8]) -> Result<Format, LoadError> {
+    if body.len() < 16 {
+        return Err(LoadError::BadHeader("fmt chunk too short".to_string()));
+    }
+
+    let mut tag = read_u16(body);
+    let container_bits = read_u16(&body[14..16]);
+    let mut valid_bits = container_bits;
+    if tag == FORMAT_EXTENSIBLE {
+        if body.len() < 40 || body[26..40] != SUBFORMAT_SUFFIX {
+            return Err(LoadError::UnsupportedFormat("unknown WAVE_FORMAT_EXTENSIBLE sub-format".to_string()));
+        }
+        valid_bits = match read_u16(&body[18..20]) {
+            0 => container_bits,
+            bits => bits.min(container_bits),
+        };
+        tag = read_u16(&body[24..26]);
+    }
+
+    let format = Format {
+        sample_format: match tag {
+            FORMAT_PCM => SampleFormat::Int,
+            FORMAT_FLOAT => SampleFormat::Float,
+            tag => return Err(LoadError::UnsupportedFormat(format!("WAVE format tag {:#06x}", tag))),
+        },
+        channels: read_u16(&body[2..4]),
+        sample_rate: read_u32(&body[4..8]),
+        container_bits,
+        valid_bits,
+    };
+
+    if format.channels == 0 {
+        return Err(LoadError::BadHeader("no channels".to_string()));
+    }
+    if format.sample_rate == 0 {
+        return Err(LoadError::BadHeader("sample rate of 0 Hz".to_string()));
+    }
+    match (format.sample_format, format.container_bits) {
+        (SampleFormat::Int, 8) | (SampleFormat::Int, 16) | (SampleFormat::Int, 24) | (SampleFormat::Int, 32) |
+        (SampleFormat::Float, 32) | (SampleFormat::Float, 64) => {},
+        (_, bits) => return Err(LoadError::UnsupportedFormat(format!("{} bit samples", bits))),
+    }
+    if read_u16(&body[12..14]) as usize != format.block_align() {
+        return Err(LoadError::BadHeader("block alignment doesn't match the sample format".to_string()));
+    }
+    Ok(format)
+}
+
+struct ChunkHeader {
+    id: [u8; 4],
+    size: u64,
+}
+
+/// Reads the next chunk header, `None` once the file is over.
+fn read_chunk_header<R: Read>(reader: &mut R, container: Container) -> io::Result<Option<ChunkHeader>> {
+    let mut header = [0; 24];
+    let header = &mut header[..container.chunk_header_size() as usize];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            // Stray bytes after the last chunk are ignored
+            Ok(0) => return Ok(None),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut id = [0; 4];
+    id.copy_from_slice(&header[0..4]);
+    Ok(Some(match container {
+        Container::Riff | Container::Rf64 => ChunkHeader { id, size: read_u32(&header[4..8]) as u64 },
+        Container::Wave64 => {
+            // Unknown GUIDs get a name no RIFF chunk has, so that they are skipped.
+            if &id == b"list" && header[4..16] == W64_LIST_SUFFIX {
+                id = *b"LIST";
+            } else if header[4..16] != W64_CHUNK_SUFFIX {
+                id = [0; 4];
+            }
+            ChunkHeader { id, size: read_u64(&header[16..24]).saturating_sub(24) }
+        },
+    }))
+}
+
+/// Reads a whole chunk body, `None` if the file ends before it does.
+fn read_body<R: Read>(reader: &mut R, size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut body = vec![];
+    reader.take(size).read_to_end(&mut body)?;
+    Ok(if body.len() as u64 == size { Some(body) } else { None })
+}
+
+/// Text of a fixed size or zero terminated string field.
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn parse_bext(body: &[u8]) -> Option<BroadcastExtension> {
+    if body.len() < BEXT_SIZE {
+        return None;
+    }
+    let mut loudness = [0; 5];
+    for (l, bytes) in loudness.iter_mut().zip(body[412..422].chunks_exact(2)) {
+        *l = i16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Some(BroadcastExtension {
+        description: read_string(&body[0..256]),
+        originator: read_string(&body[256..288]),
+        originator_reference: read_string(&body[288..320]),
+        origination_date: read_string(&body[320..330]),
+        origination_time: read_string(&body[330..338]),
+        time_reference: read_u64(&body[338..346]),
+        version: read_u16(&body[346..348]),
+        umid: body[348..412].to_vec(),
+        loudness,
+        coding_history: read_string(&body[BEXT_SIZE..]),
+    })
+}
+
+/// Identifiers and sample offsets of the points in a `cue ` chunk.
+fn parse_cue(body: &[u8]) -> Option<Vec<(u32, usize)>> {
+    let count = read_u32(body.get(0..4)?) as usize;
+    let points = body[4..].chunks_exact(24)
+        .take(count)
+        .map(|point| (read_u32(&point[0..4]), read_u32(&point[20..24]) as usize))
+        .collect();
+    Some(points)
+}
+
+/// Items of a LIST chunk, past its form type.
+fn list_items(mut body: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> + '_ {
+    iter::from_fn(move || {
+        if body.len() < 8 {
+            return None;
+        }
+        let id = body[0..4].try_into().unwrap();
+        let size = (read_u32(&body[4..8]) as usize).min(body.len() - 8);
+        let item = &body[8..8 + size];
+        body = &body[(8 + size + size % 2).min(body.len())..];
+        Some((id, item))
+    })
+}
+
+fn info_tag_name(id: &[u8; 4]) -> String {
+    match INFO_TAGS.iter().find(|(i, _)| *i == id) {
+        Some((_, name)) => name.to_string(),
+        None => String::from_utf8_lossy(id).into_owned(),
+    }
+}
+
+/// LIST-INFO item a tag is stored as, tags that are neither known nor an INFO id themselves
+/// can't be written.
+fn info_id(name: &str) -> Option<[u8; 4]> {
+    match INFO_TAGS.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)) {
+        Some((id, _)) => Some(**id),
+        None if name.len() == 4 && name.starts_with('I') && name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) =>
+            name.as_bytes().try_into().ok(),
+        None => None,
+    }
+}
+
+/// Splits the chunks the loader doesn't handle itself into tags, markers and the metadata kept
+/// along with the file. Whatever can't be parsed is kept as is.
+fn parse_metadata(chunks: Vec<Chunk>) -> (Vec<(String, String)>, Vec<Marker>, Metadata) {
+    let mut tags = vec![];
+    let mut cue_points = vec![];
+    let mut labels = HashMap::new();
+    let mut metadata = Metadata::default();
+    for chunk in chunks {
+        let parsed = match &chunk.id {
+            b"bext" if metadata.bext.is_none() => {
+                metadata.bext = parse_bext(&chunk.data);
+                metadata.bext.is_some()
+            },
+            b"iXML" if metadata.ixml.is_none() => {
+                metadata.ixml = std::str::from_utf8(&chunk.data).ok().map(|s| s.trim_end_matches('\0').to_string());
+                metadata.ixml.is_some()
+            },
+            b"cue " => parse_cue(&chunk.data).map(|points| cue_points.extend(points)).is_some(),
+            b"LIST" if chunk.data.starts_with(b"INFO") => {
+                tags.extend(list_items(&chunk.data[4..]).map(|(id, value)| (info_tag_name(&id), read_string(value))));
+                true
+            },
+            // Only labels are kept, notes and labeled text are dropped.
+            b"LIST" if chunk.data.starts_with(b"adtl") => {
+                labels.extend(list_items(&chunk.data[4..])
+                    .filter(|(id, label)| id == b"labl" && label.len() >= 4)
+                    .map(|(_, label)| (read_u32(label), read_string(&label[4..]))));
+                true
+            },
+            _ => false,
+        };
+        if !parsed {
+            metadata.chunks.push(chunk);
+        }
+    }
+
+    let mut markers: Vec<Marker> = cue_points.into_iter()
+        .map(|(id, position)| Marker { position, name: labels.remove(&id).unwrap_or_default() })
+        .collect();
+    markers.sort_by_key(|m| m.position);
+    (tags, markers, metadata)
+}
+
+/// Reads RIFF, RF64, BW64 and Wave64 files. The sample data is mapped from `file` when given,
+/// which has to be what `reader` reads, otherwise it is read into memory.
+pub fn load<R: Read + Seek>(mut reader: R, filename: &Path, file: Option<&File>) -> Result<AudioFile, LoadError> {
+    // Tags in front of the file, ID3 for instance, were already skipped.
+    let start = reader.stream_position()?;
+    let mut header = [0; 16];
+    reader.read_exact(&mut header)?;
+    let container = Container::detect(&header)
+        .ok_or_else(|| LoadError::BadHeader("not a WAVE file".to_string()))?;
+    reader.seek(SeekFrom::Start(start + container.header_size() as u64))?;
+
+    let mut format = None;
+    let mut ds64_data_size = None;
+    let mut data = None;
+    let mut chunks = vec![];
+    while let Some(chunk) = read_chunk_header(&mut reader, container)? {
+        // RF64 stores the size of large data chunks in ds64 instead
+        let size = match ds64_data_size {
+            Some(size) if chunk.id == *b"data" && chunk.size == u32::MAX as u64 => size,
+            _ => chunk.size,
+        };
+        // Bytes of the chunk left to skip once it has been handled
+        let mut remaining = size;
+        match &chunk.id {
+            b"ds64" if container == Container::Rf64 => {
+                let mut body = vec![0; chunk.size.min(1024) as usize];
+                reader.read_exact(&mut body)?;
+                if body.len() < 24 {
+                    return Err(LoadError::BadHeader("ds64 chunk too short".to_string()));
+                }
+                ds64_data_size = Some(read_u64(&body[8..16]));
+                remaining -= body.len() as u64;
+            },
+            b"fmt " => {
+                let mut body = vec![0; chunk.size.min(1024) as usize];
+                reader.read_exact(&mut body)?;
+                format = Some(parse_format(&body)?);
+                remaining -= body.len() as u64;
+            },
+            b"data" => {
+                let format = format.as_ref()
+                    .ok_or_else(|| LoadError::BadHeader("data chunk before fmt chunk".to_string()))?;
+                let start = reader.stream_position()?;
+                let bytes = match file {
+                    Some(file) => mapping::map(file, start, size)?,
+                    None => {
+                        // The size may be bogus on a truncated file, don't trust it for more than a guess.
+                        let mut bytes = Vec::with_capacity(size.min(1 << 28) as usize);
+                        (&mut reader).take(size).read_to_end(&mut bytes)?;
+                        Bytes::Owned(bytes)
+                    },
+                };
+                let truncated = (bytes.len() as u64) < size;
+                // Reading instead of mapping moves the file past the reader's back
+                reader.seek(SeekFrom::Start(start + bytes.len() as u64))?;
+                let expected_frames = (size / format.block_align() as u64) as usize;
+                data = Some((bytes, expected_frames, truncated));
+                if truncated {
+                    break;
+                }
+                remaining = 0;
+            },
+            // Sizes and padding, written anew on save
+            b"ds64" | b"fact" | b"JUNK" | b"junk" | b"PAD " | b"FLLR" | &[0, 0, 0, 0] => {},
+            _ => match read_body(&mut reader, size)? {
+                Some(data) => {
+                    chunks.push(Chunk { id: chunk.id, data });
+                    remaining = 0;
+                },
+                // Metadata cut short isn't worth keeping
+                None => break,
+            },
+        }
+        reader.seek(SeekFrom::Current((remaining + container.padding(size)) as i64))?;
+    }
+
+    let format = format.ok_or_else(|| LoadError::BadHeader("missing fmt chunk".to_string()))?;
+    let (bytes, expected_frames, truncated) = data.unwrap_or((Bytes::Owned(vec![]), 0, false));
+    let length = bytes.len();
+    let (tags, markers, metadata) = parse_metadata(chunks);
+
+    let file = AudioFile {
+        filename: filename.to_path_buf(),
+        samples: PcmSource::new(bytes, 0..length, format.layout()).into_store(),
+        channels: format.channels,
+        sample_rate: format.sample_rate,
+        bit_depth: match format.sample_format {
+            SampleFormat::Float => BitDepth::Float32,
+            SampleFormat::Int => int_bit_depth(format.valid_bits),
+        },
+        tags,
+        markers,
+        loop_points: None,
+        metadata,
+        headerless: false,
+    };
+
+    if truncated {
+        Err(LoadError::Truncated { file: Box::new(file), expected_frames })
+    } else {
+        Ok(file)
+    }
+}
+
+/// Speaker positions of the usual layouts, 0 lets the player decide for the others.
+fn channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        3 => 0x7,
+        4 => 0x33,
+        5 => 0x37,
+        6 => 0x3f,
+        8 => 0x63f,
+        _ => 0,
+    }
+}
+
+fn format_chunk(file: &AudioFile, options: &WriteOptions) -> Vec<u8> {
+    let float = options.bit_depth == BitDepth::Float32;
+    let bits = options.bit_depth.bits();
+    let block_align = file.channels * bits / 8;
+    let tag = if float { FORMAT_FLOAT } else { FORMAT_PCM };
+    // Multichannel and high resolution PCM need WAVE_FORMAT_EXTENSIBLE to be read unambiguously.
+    let extensible = file.channels > 2 || (!float && bits > 16);
+
+    let mut body = vec![];
+    body.extend_from_slice(&(if extensible { FORMAT_EXTENSIBLE } else { tag }).to_le_bytes());
+    body.extend_from_slice(&file.channels.to_le_bytes());
+    body.extend_from_slice(&file.sample_rate.to_le_bytes());
+    body.extend_from_slice(&(file.sample_rate * block_align as u32).to_le_bytes());
+    body.extend_from_slice(&block_align.to_le_bytes());
+    body.extend_from_slice(&bits.to_le_bytes());
+    if extensible {
+        body.extend_from_slice(&22u16.to_le_bytes());
+        body.extend_from_slice(&bits.to_le_bytes());
+        body.extend_from_slice(&channel_mask(file.channels).to_le_bytes());
+        body.extend_from_slice(&tag.to_le_bytes());
+        body.extend_from_slice(&SUBFORMAT_SUFFIX);
+    } else if float {
+        body.extend_from_slice(&0u16.to_le_bytes());
+    }
+    body
+}
+
+fn bext_chunk(bext: &BroadcastExtension) -> Vec<u8> {
+    let mut body = vec![];
+    let fields = [
+        (&bext.description, 256),
+        (&bext.originator, 32),
+        (&bext.originator_reference, 32),
+        (&bext.origination_date, 10),
+        (&bext.origination_time, 8),
+    ];
+    for (text, size) in &fields {
+        let bytes = &text.as_bytes()[..text.len().min(*size)];
+        body.extend_from_slice(bytes);
+        body.resize(body.len() + size - bytes.len(), 0);
+    }
+    body.extend_from_slice(&bext.time_reference.to_le_bytes());
+    body.extend_from_slice(&bext.version.to_le_bytes());
+    body.extend(bext.umid.iter().copied().chain(iter::repeat(0)).take(64));
+    for l in &bext.loudness {
+        body.extend_from_slice(&l.to_le_bytes());
+    }
+    body.resize(BEXT_SIZE, 0);
+    body.extend_from_slice(bext.coding_history.as_bytes());
+    body
+}
+
+/// Cue points are numbered from 1 in the order of the markers.
+fn cue_chunk(markers: &[Marker]) -> Vec<u8> {
+    let mut body = (markers.len() as u32).to_le_bytes().to_vec();
+    for (i, marker) in markers.iter().enumerate() {
+        let position = marker.position.min(u32::MAX as usize) as u32;
+        body.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+        body.extend_from_slice(&position.to_le_bytes());
+        body.extend_from_slice(b"data");
+        // Chunk and block start, only used by wavl chunks
+        body.extend_from_slice(&[0; 8]);
+        body.extend_from_slice(&position.to_le_bytes());
+    }
+    body
+}
+
+fn list_chunk(form: &[u8; 4], items: Vec<([u8; 4], Vec<u8>)>) -> Vec<u8> {
+    let mut body = form.to_vec();
+    for (id, item) in items {
+        body.extend_from_slice(&id);
+        body.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        body.extend_from_slice(&item);
+        body.resize(body.len() + item.len() % 2, 0);
+    }
+    body
+}
+
+fn zero_terminated(text: &str) -> Vec<u8> {
+    text.bytes().chain(iter::once(0)).collect()
+}
+
+/// Everything besides fmt and data, in the order it goes in the file.
+fn metadata_chunks(file: &AudioFile) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut chunks = vec![];
+    if let Some(bext) = &file.metadata.bext {
+        chunks.push((*b"bext", bext_chunk(bext)));
+    }
+    if !file.markers.is_empty() {
+        chunks.push((*b"cue ", cue_chunk(&file.markers)));
+        let labels: Vec<_> = file.markers.iter().enumerate()
+            .filter(|(_, marker)| !marker.name.is_empty())
+            .map(|(i, marker)| {
+                let mut label = (i as u32 + 1).to_le_bytes().to_vec();
+                label.extend(zero_terminated(&marker.name));
+                (*b"labl", label)
+            })
+            .collect();
+        if !labels.is_empty() {
+            chunks.push((*b"LIST", list_chunk(b"adtl", labels)));
+        }
+    }
+    let info: Vec<_> = file.tags.iter()
+        .filter_map(|(name, value)| Some((info_id(name)?, zero_terminated(value))))
+        .collect();
+    if !info.is_empty() {
+        chunks.push((*b"LIST", list_chunk(b"INFO", info)));
+    }
+    if let Some(ixml) = &file.metadata.ixml {
+        chunks.push((*b"iXML", ixml.as_bytes().to_vec()));
+    }
+    chunks.extend(file.metadata.chunks.iter().map(|chunk| (chunk.id, chunk.data.clone())));
+    chunks
+}
+
+fn write_chunk_header(bytes: &mut Vec<u8>, id: &[u8; 4], size: u64, container: Container) {
+    match container {
+        Container::Riff => {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        },
+        // The real size is in the ds64 chunk when it doesn't fit
+        Container::Rf64 => {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&(size.min(u32::MAX as u64) as u32).to_le_bytes());
+        },
+        // LIST is the one RIFF chunk Wave64 gives a GUID of its own
+        Container::Wave64 if id == b"LIST" => {
+            bytes.extend_from_slice(b"list");
+            bytes.extend_from_slice(&W64_LIST_SUFFIX);
+            bytes.extend_from_slice(&(size + 24).to_le_bytes());
+        },
+        Container::Wave64 => {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&W64_CHUNK_SUFFIX);
+            bytes.extend_from_slice(&(size + 24).to_le_bytes());
+        },
+    }
+}
+
+pub fn write<W: Write>(mut output: W, file: &AudioFile, options: &WriteOptions, container: Container) -> io::Result<()> {
+    let sample_size = options.bit_depth.bits() as usize / 8;
+    let frames = file.frames();
+    let data_size = (frames * file.channels as usize * sample_size) as u64;
+
+    let mut chunks = vec![];
+    if container == Container::Rf64 {
+        chunks.push((*b"ds64", vec![0; 28]));
+    }
+    chunks.push((*b"fmt ", format_chunk(file, options)));
+    chunks.extend(metadata_chunks(file));
+    let chunk_size = |size: u64| container.chunk_header_size() + size + container.padding(size);
+    let form_size = chunks.iter().map(|(_, body)| chunk_size(body.len() as u64)).sum::<u64>() + chunk_size(data_size);
+
+    let mut header = vec![];
+    match container {
+        Container::Riff | Container::Rf64 => {
+            let riff_size = 4 + form_size;
+            header.extend_from_slice(if container == Container::Riff { b"RIFF" } else { b"RF64" });
+            header.extend_from_slice(&(riff_size.min(u32::MAX as u64) as u32).to_le_bytes());
+            header.extend_from_slice(b"WAVE");
+            if let Some((_, ds64)) = chunks.iter_mut().find(|(id, _)| id == b"ds64") {
+                ds64[0..8].copy_from_slice(&riff_size.to_le_bytes());
+                ds64[8..16].copy_from_slice(&data_size.to_le_bytes());
+                ds64[16..24].copy_from_slice(&(frames as u64).to_le_bytes());
+            }
+        },
+        Container::Wave64 => {
+            header.extend_from_slice(b"riff");
+            header.extend_from_slice(&W64_RIFF_SUFFIX);
+            header.extend_from_slice(&(40 + form_size).to_le_bytes());
+            header.extend_from_slice(b"wave");
+            header.extend_from_slice(&W64_CHUNK_SUFFIX);
+        },
+    }
+    for (id, body) in &chunks {
+        write_chunk_header(&mut header, id, body.len() as u64, container);
+        header.extend_from_slice(body);
+        header.resize(header.len() + container.padding(body.len() as u64) as usize, 0);
+    }
+    write_chunk_header(&mut header, b"data", data_size, container);
+    output.write_all(&header)?;
+
+    let mut quantizers = quantizers(options.bit_depth.bits(), file.channels, options);
+    let channels = file.channels as usize;
+    let mut buffer = vec![];
+    for_each_block(&file.samples, |block| {
+        buffer.clear();
+        for (i, &s) in block.iter().enumerate() {
+            let quantizer = &mut quantizers[i % channels];
+            match (options.bit_depth, sample_size) {
+                (BitDepth::Float32, _) => buffer.extend_from_slice(&s.to_le_bytes()),
+                (_, 1) => buffer.push((quantizer.quantize(s) + 128) as u8),
+                (_, size) => buffer.extend_from_slice(&quantizer.quantize(s).to_le_bytes()[..size]),
+            }
+        }
+        output.write_all(&buffer)
+    })?;
+    output.write_all(&[0; 8][..container.padding(data_size) as usize])?;
+    output.flush()
+}
+
+/// Writes a RIFF file, promoted to RF64 when the data doesn't fit in 4 GB.
+pub fn save<W: Write>(output: W, file: &AudioFile, options: &WriteOptions) -> io::Result<()> {
+    // Leave room for the header and the parsed metadata
+    const RIFF_LIMIT: u64 = u32::MAX as u64 - (1 << 20);
+    let data_size = (file.frames() * file.channels as usize) as u64 * options.bit_depth.bits() as u64 / 8;
+    let chunks_size: u64 = file.metadata.chunks.iter().map(|chunk| chunk.data.len() as u64 + 9).sum();
+    let container = if data_size + chunks_size > RIFF_LIMIT { Container::Rf64 } else { Container::Riff };
+    write(output, file, options, container)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use waved_core::samples::SampleStore;
+
+    #[test]
+    fn test_zero_sample_rate() {
+        let file = AudioFile::from_store(SampleStore::from_planar(vec![0.0; 4], 1), 8000);
+        let mut bytes = vec![];
+        save(&mut bytes, &file, &WriteOptions::new(BitDepth::Int16)).unwrap();
+        bytes[24..28].copy_from_slice(&[0; 4]);
+        assert!(matches!(load(Cursor::new(bytes), Path::new("zero.wav"), None), Err(LoadError::BadHeader(_))));
+    }
+
+    #[test]
+    fn test_rf64_round_trip() {
+        let file = AudioFile::from_store(SampleStore::from_planar(vec![0.0, 0.5, -0.5, 1.0, 0.25, -1.0], 3), 48000);
+        let mut bytes = vec![];
+        write(&mut bytes, &file, &WriteOptions::new(BitDepth::Int24), Container::Rf64).unwrap();
+        assert_eq!(&bytes[0..4], b"RF64");
+        assert_eq!(&bytes[12..16], b"ds64");
+
+        // Pretend the data is over 4 GB, only the ds64 chunk knows its size then.
+        let data = bytes.windows(4).position(|w| w == b"data").unwrap();
+        bytes[data + 4..data + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let loaded = load(Cursor::new(bytes), Path::new("test.wav"), None).unwrap();
+        assert_eq!((loaded.channels, loaded.sample_rate, loaded.bit_depth), (3, 48000, BitDepth::Int24));
+        for c in 0..3 {
+            for (l, s) in loaded.samples.channel(c).iter().zip(&file.samples.channel(c)) {
+                assert!((l - s).abs() < 1e-6, "{} != {}", l, s);
+            }
+        }
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let bext = BroadcastExtension {
+            description: "Scene 12, take 3".to_string(),
+            originator: "Recorder".to_string(),
+            origination_date: "2020-06-01".to_string(),
+            origination_time: "14:30:00".to_string(),
+            time_reference: 48000 * 3600 * 14,
+            version: 2,
+            umid: vec![0; 64],
+            loudness: [-2300, 800, -100, -1800, -2000],
+            coding_history: "A=PCM,F=48000,W=24,M=stereo\r\n".to_string(),
+            ..Default::default()
+        };
+        let ixml = "<?xml version=\"1.0\"?><BWFXML><SCENE>12</SCENE><TAKE>3</TAKE></BWFXML>";
+        let file = AudioFile {
+            tags: vec![
+                ("TITLE".to_string(), "Ambience".to_string()),
+                ("ISBJ".to_string(), "Forest".to_string()),
+                ("REPLAYGAIN_TRACK_GAIN".to_string(), "-3 dB".to_string()),
+            ],
+            markers: vec![
+                Marker { position: 1, name: "Clap".to_string() },
+                Marker { position: 3, name: String::new() },
+            ],
+            metadata: Metadata {
+                bext: Some(bext),
+                ixml: Some(ixml.to_string()),
+                chunks: vec![Chunk { id: *b"smpl", data: vec![1, 2, 3] }],
+            },
+            ..AudioFile::from_store(SampleStore::from_planar(vec![0.0; 8], 2), 48000)
+        };
+
+        for &container in &[Container::Riff, Container::Wave64] {
+            let mut bytes = vec![];
+            write(&mut bytes, &file, &WriteOptions::new(BitDepth::Int24), container).unwrap();
+            let loaded = load(Cursor::new(bytes), Path::new("test.wav"), None).unwrap();
+            assert_eq!(loaded.metadata, file.metadata);
+            assert_eq!(loaded.metadata.ixml_value("TAKE"), Some("3"));
+            assert_eq!(loaded.markers, file.markers);
+            // Tags that have no INFO id are lost
+            assert_eq!(loaded.tags, &file.tags[0..2]);
+            assert_eq!(loaded.frames(), 4);
+        }
+    }
+
+    #[test]
+    fn test_wave64_list_guid() {
+        // Written by hand from the GUIDs Sony documents, LIST is 7473696C-912F-11CF-A5D6-28DB04C10000
+        let guid = |name: &[u8; 4], suffix: &[u8]| [&name[..], suffix].concat();
+        let riff = guid(b"riff", &[0x2e, 0x91, 0xcf, 0x11, 0xa5, 0xd6, 0x28, 0xdb, 0x04, 0xc1, 0x00, 0x00]);
+        let list = guid(b"list", &[0x2f, 0x91, 0xcf, 0x11, 0xa5, 0xd6, 0x28, 0xdb, 0x04, 0xc1, 0x00, 0x00]);
+        let chunk = |name: &[u8; 4]| guid(name, &[0xf3, 0xac, 0xd3, 0x11, 0x8c, 0xd1, 0x00, 0xc0, 0x4f, 0x8e, 0xdb, 0x8a]);
+
+        let fmt = [1u16.to_le_bytes(), 1u16.to_le_bytes()].concat().into_iter()
+            .chain(8000u32.to_le_bytes()).chain(16000u32.to_le_bytes())
+            .chain(2u16.to_le_bytes()).chain(16u16.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let info = [&b"INFOINAM"[..], &6u32.to_le_bytes(), b"Hello\0"].concat();
+        let mut bytes = [riff, 160u64.to_le_bytes().to_vec(), chunk(b"wave")].concat();
+        for (id, body) in [(chunk(b"fmt "), fmt), (list.clone(), info), (chunk(b"data"), vec![0, 0x40, 0, 0xc0])] {
+            bytes.extend(id);
+            bytes.extend((24 + body.len() as u64).to_le_bytes());
+            bytes.extend(&body);
+            bytes.resize(bytes.len().next_multiple_of(8), 0);
+        }
+        assert_eq!(bytes.len(), 160);
+
+        let loaded = load(Cursor::new(bytes), Path::new("test.w64"), None).unwrap();
+        assert_eq!(loaded.tags, [("TITLE".to_string(), "Hello".to_string())]);
+        assert!(loaded.metadata.chunks.is_empty());
+        assert_eq!(loaded.frames(), 2);
+
+        let mut written = vec![];
+        write(&mut written, &loaded, &WriteOptions::new(BitDepth::Int16), Container::Wave64).unwrap();
+        assert!(written.windows(16).any(|w| w == list));
+    }
+}
@@ -1,4 +1,9 @@
 pub mod algorithm;
+pub mod channels;
+pub mod dither;
 pub mod io;
+pub mod loader;
+pub mod peakfile;
 pub mod playback;
+pub mod resample;
 pub mod generator;
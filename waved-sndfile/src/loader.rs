@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryIter};
+use std::thread;
+
+use waved_core::peaks::{Peak, Peaks, PEAK_FRAMES};
+use waved_core::state::{AudioFile, LoadStage};
+
+use crate::io::{raw_samples_from_file, samples_from_file_with_progress, LoadError, RawLayout};
+use crate::peakfile;
+
+/// Frames scanned for peaks between two reports.
+const SCAN_FRAMES: usize = PEAK_FRAMES * 1024;
+
+pub enum LoadEvent {
+    /// Fraction of the current stage done.
+    Progress(LoadStage, f32),
+    /// The file was decoded, its peaks are being computed.
+    Decoded { frames: usize, channels: usize },
+    /// Peaks that follow the ones sent before.
+    Peaks(Vec<Vec<Peak>>),
+    /// The load is over. A recovered file comes with all of its peaks, like a complete one.
+    Done(Box<Result<AudioFile, LoadError>>),
+}
+
+/// Loads a file on a worker thread, reporting how it goes. Dropping it cancels the load.
+pub struct Loader {
+    events: Receiver<LoadEvent>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Loader {
+    /// Starts loading `filename`, `layout` being required for headerless files.
+    pub fn spawn(filename: PathBuf, layout: Option<RawLayout>) -> Self {
+        let (sender, events) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker_cancelled = cancelled.clone();
+        thread::spawn(move || load(&filename, layout.as_ref(), &sender, &worker_cancelled));
+        Self { events, cancelled }
+    }
+
+    /// Events sent since the last call, without waiting for more.
+    pub fn events(&self) -> TryIter<'_, LoadEvent> {
+        self.events.try_iter()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn load(filename: &Path, layout: Option<&RawLayout>, events: &Sender<LoadEvent>, cancelled: &AtomicBool) {
+    // Gives up as soon as the load is cancelled or nobody is listening anymore.
+    let report = |event| events.send(event).is_ok() && !cancelled.load(Ordering::Relaxed);
+
+    let mut reported = 0.0;
+    let result = match layout {
+        Some(layout) => raw_samples_from_file(filename, layout),
+        None => samples_from_file_with_progress(filename, |fraction| {
+            // Decoders read in small pieces, only report whole percents.
+            if fraction - reported >= 0.01 {
+                reported = fraction;
+                report(LoadEvent::Progress(LoadStage::Decoding, fraction))
+            } else {
+                !cancelled.load(Ordering::Relaxed)
+            }
+        }),
+    };
+
+    let file = match &result {
+        Ok(file) => Some(file),
+        Err(LoadError::Truncated { file, .. }) => Some(&**file),
+        Err(_) => None,
+    };
+    if let Some(file) = file {
+        let frames = file.frames();
+        let channels = file.channels as usize;
+        if !report(LoadEvent::Decoded { frames, channels }) {
+            return;
+        }
+
+        // Headerless files could be read with another layout next time, and recovered ones
+        // aren't what's on disk.
+        let use_sidecar = layout.is_none() && result.is_ok() &&
+            fs::metadata(filename).is_ok_and(|m| m.len() >= peakfile::MIN_FILE_SIZE);
+        match use_sidecar.then(|| peakfile::read(filename, frames, channels)).flatten() {
+            Some(peaks) => {
+                if !report(LoadEvent::Peaks(peaks.channels)) || !report(LoadEvent::Progress(LoadStage::Scanning, 1.0)) {
+                    return;
+                }
+            },
+            None => {
+                let mut scanned = Peaks::new(channels);
+                for start in (0..frames).step_by(SCAN_FRAMES) {
+                    let end = (start + SCAN_FRAMES).min(frames);
+                    let peaks = Peaks::scan(&file.samples, start..end);
+                    if use_sidecar {
+                        scanned.append(peaks.clone());
+                    }
+                    if !report(LoadEvent::Peaks(peaks)) ||
+                        !report(LoadEvent::Progress(LoadStage::Scanning, end as f32 / frames as f32)) {
+                        return;
+                    }
+                }
+                if use_sidecar {
+                    // Not being able to write next to the file only means scanning it again
+                    peakfile::write(filename, frames, &scanned).ok();
+                }
+            },
+        }
+    }
+    events.send(LoadEvent::Done(Box::new(result))).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{samples_to_file, WriteOptions};
+    use waved_core::samples::SampleStore;
+    use waved_core::state::BitDepth;
+
+    #[test]
+    fn test_load_in_background() {
+        let path = std::env::temp_dir().join("waved-loader.flac");
+        let frames = SCAN_FRAMES + 1000;
+        let samples: Vec<f32> = (0..frames * 2).map(|i| (i as f32 * 0.001).sin() * 0.5).collect();
+        let file = AudioFile::from_store(SampleStore::from_planar(samples, 2), 44100);
+        samples_to_file(&path, &file, &WriteOptions::new(BitDepth::Int16)).unwrap();
+
+        let loader = Loader::spawn(path.clone(), None);
+        let mut peaks = Peaks::default();
+        let mut stages = vec![];
+        let loaded = loop {
+            match loader.events.recv().unwrap() {
+                LoadEvent::Progress(stage, _) => stages.push(stage),
+                LoadEvent::Decoded { frames, channels } => assert_eq!((frames, channels), (file.frames(), 2)),
+                LoadEvent::Peaks(new) => peaks.append(new),
+                LoadEvent::Done(result) => break result.unwrap(),
+            }
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.frames(), frames);
+        assert_eq!(peaks.len(), frames.div_ceil(PEAK_FRAMES));
+        assert_eq!(stages.first(), Some(&LoadStage::Decoding));
+        assert_eq!(stages.last(), Some(&LoadStage::Scanning));
+    }
+}
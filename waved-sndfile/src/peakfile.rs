@@ -0,0 +1,176 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use waved_core::peaks::{Peak, Peaks, PEAK_FRAMES};
+
+const MAGIC: &[u8; 4] = b"WVPK";
+/// Bumped whenever the layout of the file or the way peaks are computed changes.
+const VERSION: u32 = 1;
+
+/// Files smaller than this are scanned faster than their sidecar is checked for.
+pub const MIN_FILE_SIZE: u64 = 16 << 20;
+
+/// Longest path a sidecar may record, anything longer comes from a corrupt file.
+const MAX_PATH_LENGTH: usize = 4096;
+
+/// Where the peaks of `filename` are kept, next to it.
+pub fn sidecar_path(filename: &Path) -> PathBuf {
+    let mut path = filename.as_os_str().to_owned();
+    path.push(".peaks");
+    PathBuf::from(path)
+}
+
+/// What a sidecar was computed from, it's stale as soon as any of it changes.
+#[derive(Debug, PartialEq)]
+struct Key {
+    path: String,
+    size: u64,
+    modified: (u64, u32),
+}
+
+impl Key {
+    fn of(filename: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(filename)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_err(io::Error::other)?;
+        Ok(Self {
+            path: fs::canonicalize(filename)?.to_string_lossy().into_owned(),
+            size: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+        })
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+fn read_key<R: Read>(reader: &mut R) -> io::Result<Key> {
+    let length = read_u32(reader)? as usize;
+    if length > MAX_PATH_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "path in peak file is too long"));
+    }
+    let mut path = vec![0; length];
+    reader.read_exact(&mut path)?;
+    Ok(Key {
+        path: String::from_utf8_lossy(&path).into_owned(),
+        size: read_u64(reader)?,
+        modified: (read_u64(reader)?, read_u32(reader)?),
+    })
+}
+
+fn read_sidecar(filename: &Path, frames: usize, channels: usize) -> io::Result<Option<Peaks>> {
+    let mut reader = BufReader::new(File::open(sidecar_path(filename))?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION || read_key(&mut reader)? != Key::of(filename)? {
+        return Ok(None);
+    }
+    if read_u32(&mut reader)? as usize != PEAK_FRAMES ||
+        read_u64(&mut reader)? as usize != frames ||
+        read_u32(&mut reader)? as usize != channels {
+        return Ok(None);
+    }
+
+    let count = frames.div_ceil(PEAK_FRAMES);
+    let mut peaks = Peaks::new(channels);
+    for channel in &mut peaks.channels {
+        channel.reserve_exact(count);
+        for _ in 0..count {
+            channel.push(Peak { min: read_f32(&mut reader)?, max: read_f32(&mut reader)?, rms: read_f32(&mut reader)? });
+        }
+    }
+    Ok(Some(peaks))
+}
+
+/// Peaks of `filename` saved by `write`, unless the file changed since or they don't match its
+/// decoded length.
+pub fn read(filename: &Path, frames: usize, channels: usize) -> Option<Peaks> {
+    read_sidecar(filename, frames, channels).ok().flatten()
+}
+
+/// Saves the peaks of all `frames` frames of `filename` for the next time it's opened.
+pub fn write(filename: &Path, frames: usize, peaks: &Peaks) -> io::Result<()> {
+    let key = Key::of(filename)?;
+    let path = sidecar_path(filename);
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".part");
+
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(key.path.len() as u32).to_le_bytes())?;
+        writer.write_all(key.path.as_bytes())?;
+        writer.write_all(&key.size.to_le_bytes())?;
+        writer.write_all(&key.modified.0.to_le_bytes())?;
+        writer.write_all(&key.modified.1.to_le_bytes())?;
+        writer.write_all(&(PEAK_FRAMES as u32).to_le_bytes())?;
+        writer.write_all(&(frames as u64).to_le_bytes())?;
+        writer.write_all(&(peaks.channels.len() as u32).to_le_bytes())?;
+        for channel in &peaks.channels {
+            for peak in channel {
+                writer.write_all(&peak.min.to_le_bytes())?;
+                writer.write_all(&peak.max.to_le_bytes())?;
+                writer.write_all(&peak.rms.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    })();
+    match result {
+        Ok(()) => fs::rename(&temporary, path),
+        Err(err) => {
+            fs::remove_file(&temporary).ok();
+            Err(err)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar_invalidation() {
+        let filename = std::env::temp_dir().join("waved-peakfile.raw");
+        fs::write(&filename, [0u8; 64]).unwrap();
+
+        let frames = PEAK_FRAMES * 2 + 1;
+        let mut peaks = Peaks::new(2);
+        for (c, channel) in peaks.channels.iter_mut().enumerate() {
+            *channel = (0..3).map(|i| Peak { min: -(i as f32), max: c as f32, rms: 0.5 }).collect();
+        }
+        write(&filename, frames, &peaks).unwrap();
+
+        assert_eq!(read(&filename, frames, 2).unwrap().channels, peaks.channels);
+        assert!(read(&filename, frames + PEAK_FRAMES, 2).is_none());
+        assert!(read(&filename, frames, 1).is_none());
+
+        // Rewriting the file makes the sidecar stale
+        fs::write(&filename, [0u8; 65]).unwrap();
+        assert!(read(&filename, frames, 2).is_none());
+
+        // A corrupt path length is rejected rather than allocated
+        let mut corrupt = MAGIC.to_vec();
+        corrupt.extend_from_slice(&VERSION.to_le_bytes());
+        corrupt.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(sidecar_path(&filename), corrupt).unwrap();
+        assert_eq!(read_sidecar(&filename, frames, 2).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(sidecar_path(&filename)).unwrap();
+        fs::remove_file(&filename).unwrap();
+    }
+}
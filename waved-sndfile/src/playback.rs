@@ -1,71 +1,786 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
 use std::time::Duration;
-use std::iter::Iterator;
 
-use cpal::{self, StreamData, UnknownTypeOutputBuffer, SampleFormat};
+use cpal::{self, EventLoop, Format, StreamData, UnknownTypeOutputBuffer, SampleFormat};
 use cpal::traits::{HostTrait, DeviceTrait, EventLoopTrait};
 
 use ringbuf::{self, RingBuffer};
 
-use crate::generator;
-
-pub fn create_audio_thread(buffer_size: usize) {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("No output device available.");
-
-    // TODO: We may need to support other sample formats depending on what
-    // the target systems provide.
-    let mut supported_formats_range = device.supported_output_formats()
-        .expect("Error while querying formats.");
-    let format = supported_formats_range.find(|f| f.data_type == SampleFormat::F32)
-        .expect("No supported device format.")
-        .with_max_sample_rate();
-    let channels = format.channels;
-    let sample_rate = format.sample_rate.0;
-
-    // TODO: Can we know the size of the underlying device buffer?
-    let ring = RingBuffer::<f32>::new(buffer_size);
-    let (mut producer, mut consumer) = ring.split();
-
-    // Spawn audio mixing thread.
-    thread::spawn(move || {
-        // TODO: Implement mixing / filtering signal chain
-        let t_sleep = buffer_size as f32 / sample_rate as f32 * 0.5;
-        let mut sine = generator::sine(sample_rate, 500.0);
-        loop {
-            while !producer.is_full() {
-                producer.push(sine.next().unwrap()).unwrap();
+use waved_core::samples::SampleStore;
+use waved_core::state::{AudioFile, Playback, TransportStatus};
+
+use crate::channels::{ChannelMap, Downmix};
+use crate::dither::{Dither, NoiseShaping, Quantizer};
+use crate::resample::{Quality, Resampler};
+
+pub enum Command {
+    Load(Arc<AudioFile>),
+    /// Swaps in an edit of the loaded file, playback carries on from where it was.
+    Replace(Arc<AudioFile>),
+    Play,
+    Pause,
+    Stop,
+    SetDownmix(Downmix),
+    SetQuality(Quality),
+    SetLoop(Option<LoopRegion>),
+}
+
+/// Range of frames repeated during playback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopRegion {
+    pub start: usize,
+    pub end: usize,
+    /// Length in frames of the crossfade between the end of the loop and its start, hides the
+    /// click caused by the discontinuity at the loop point.
+    pub crossfade: usize,
+}
+
+/// Channel layout and sample rate of the buffers a backend pulls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputConfig {
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+/// Fills a buffer of interleaved frames in the backend's `OutputConfig`, given how many frames
+/// will still be queued ahead of the listener once the buffer is handed over.
+pub type RenderCallback = Box<dyn FnMut(&mut [f32], usize) + Send>;
+
+/// Something that periodically pulls rendered audio, usually an output device.
+pub trait AudioBackend {
+    fn config(&self) -> OutputConfig;
+
+    /// Starts pulling buffers from `render` until the application exits.
+    fn start(self, render: RenderCallback);
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    NoDevice,
+    NoSupportedFormat,
+    QueryFormats(String),
+    /// The device rejected the format, or is in use by another application.
+    BuildStream(String),
+    PlayStream(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::NoDevice => write!(f, "No output device available."),
+            BackendError::NoSupportedFormat => write!(f, "No supported device format."),
+            BackendError::QueryFormats(err) => write!(f, "Error while querying formats: {}", err),
+            BackendError::BuildStream(err) => write!(f, "Error while opening the output stream: {}", err),
+            BackendError::PlayStream(err) => write!(f, "Error while starting the output stream: {}", err),
+        }
+    }
+}
+
+impl Error for BackendError {}
+
+/// Transport position published by the audio thread, readable from any thread without locking.
+#[derive(Default)]
+struct SharedPosition {
+    frames: AtomicUsize,
+    status: AtomicU8,
+    /// Commands processed so far, the status is up to date with all of them.
+    commands: AtomicUsize,
+}
+
+impl SharedPosition {
+    fn store(&self, frames: usize, status: TransportStatus, commands: usize) {
+        self.frames.store(frames, Ordering::Relaxed);
+        self.status.store(status as u8, Ordering::Relaxed);
+        self.commands.store(commands, Ordering::Release);
+    }
+
+    fn load(&self) -> Playback {
+        let status = match self.status.load(Ordering::Relaxed) {
+            s if s == TransportStatus::Playing as u8 => TransportStatus::Playing,
+            s if s == TransportStatus::Paused as u8 => TransportStatus::Paused,
+            _ => TransportStatus::Stopped,
+        };
+        Playback { position: self.frames.load(Ordering::Relaxed), status }
+    }
+
+    fn commands(&self) -> usize {
+        self.commands.load(Ordering::Acquire)
+    }
+}
+
+/// Handle used by the GUI thread to drive playback on the audio thread.
+pub struct Transport {
+    commands: Sender<Command>,
+    position: Arc<SharedPosition>,
+    sent: Cell<usize>,
+    /// Whether the last of the commands sent asked for playback.
+    playing: Cell<bool>,
+}
+
+impl Transport {
+    pub fn load(&self, file: Arc<AudioFile>) {
+        self.playing.set(false);
+        self.send(Command::Load(file));
+    }
+
+    pub fn replace(&self, file: Arc<AudioFile>) {
+        self.send(Command::Replace(file));
+    }
+
+    pub fn play(&self) {
+        self.playing.set(true);
+        self.send(Command::Play);
+    }
+
+    pub fn pause(&self) {
+        self.playing.set(false);
+        self.send(Command::Pause);
+    }
+
+    pub fn stop(&self) {
+        self.playing.set(false);
+        self.send(Command::Stop);
+    }
+
+    pub fn set_downmix(&self, downmix: Downmix) {
+        self.send(Command::SetDownmix(downmix));
+    }
+
+    pub fn set_quality(&self, quality: Quality) {
+        self.send(Command::SetQuality(quality));
+    }
+
+    /// Repeats a range of the file seamlessly, `None` plays through to the end again.
+    pub fn set_loop(&self, region: Option<LoopRegion>) {
+        self.send(Command::SetLoop(region));
+    }
+
+    pub fn toggle(&self) {
+        // The published status lags behind commands the audio thread hasn't got to yet, go by
+        // what was asked until it catches up. Then trust it, playback stops on its own at the end.
+        let playing = if self.position.commands() == self.sent.get() {
+            self.position().status == TransportStatus::Playing
+        } else {
+            self.playing.get()
+        };
+        if playing {
+            self.pause();
+        } else {
+            self.play();
+        }
+    }
+
+    /// Frames heard so far and current status, as last published by the audio thread.
+    pub fn position(&self) -> Playback {
+        self.position.load()
+    }
+
+    fn send(&self, command: Command) {
+        // The audio thread only hangs up if it panicked, there is nothing left to drive.
+        self.commands.send(command).ok();
+        self.sent.set(self.sent.get() + 1);
+    }
+}
+
+struct Player {
+    commands: Receiver<Command>,
+    processed: usize,
+    shared_position: Arc<SharedPosition>,
+    /// Frames rendered since the player was created, in the device's sample rate.
+    rendered: u64,
+    /// Position reached once the device plays up to a given number of rendered frames, oldest
+    /// first. Buffers are rendered ahead of what is heard, the published position follows these.
+    checkpoints: VecDeque<(u64, usize)>,
+    file: Option<Arc<AudioFile>>,
+    // Fractional frame position within the file, advances by the resampling ratio.
+    position: f64,
+    status: TransportStatus,
+    device_channels: usize,
+    device_sample_rate: u32,
+    downmix: Downmix,
+    map: Option<ChannelMap>,
+    quality: Quality,
+    resampler: Option<Resampler>,
+    looping: Option<LoopRegion>,
+    frame: Vec<f32>,
+    fade_frame: Vec<f32>,
+    weights: Vec<f32>,
+    window: Window,
+    // The crossfade of a loop reads from its start while playing its end.
+    fade_window: Window,
+}
+
+/// Frames copied out of the store at once by the audio thread.
+const WINDOW_FRAMES: usize = 4096;
+
+/// Frames of every channel around a play position, copied out of the store a block at a time so
+/// that the audio thread rarely has to go through the page cache.
+#[derive(Default)]
+struct Window {
+    start: usize,
+    channels: Vec<Vec<f32>>,
+}
+
+impl Window {
+    /// Makes sure frames `first..first + length` are in the window, leaving out those before the
+    /// start of the file. Returns where `first` is in the window.
+    fn fetch(&mut self, samples: &SampleStore, first: isize, length: usize) -> isize {
+        let start = first.max(0) as usize;
+        let end = (first + length as isize).max(0) as usize;
+        let cached = self.channels.first().map_or(0, Vec::len);
+        if self.channels.is_empty() || start < self.start || end > self.start + cached {
+            self.start = start;
+            self.channels.resize(samples.channels(), vec![]);
+            for (c, channel) in self.channels.iter_mut().enumerate() {
+                channel.resize(WINDOW_FRAMES.max(end - start), 0.0);
+                samples.read(c, start, channel);
+            }
+        }
+        first - self.start as isize
+    }
+}
+
+/// Reads the frame at the fractional `position`, interpolating between frames when resampling.
+fn read_frame(file: &AudioFile, window: &mut Window, resampler: Option<&Resampler>, weights: &mut Vec<f32>, position: f64, frame: &mut [f32]) {
+    match resampler {
+        Some(resampler) => {
+            let first = resampler.weights(position, weights);
+            let offset = window.fetch(&file.samples, first, weights.len());
+            for (c, s) in frame.iter_mut().enumerate() {
+                *s = Resampler::apply(&window.channels[c], offset, weights);
+            }
+        },
+        None => {
+            let offset = window.fetch(&file.samples, position as isize, 1) as usize;
+            for (c, s) in frame.iter_mut().enumerate() {
+                *s = window.channels[c][offset];
+            }
+        },
+    }
+}
+
+impl Player {
+    fn new(commands: Receiver<Command>, shared_position: Arc<SharedPosition>, config: OutputConfig) -> Self {
+        Self {
+            commands,
+            processed: 0,
+            shared_position,
+            rendered: 0,
+            checkpoints: VecDeque::from(vec![(0, 0)]),
+            file: None,
+            position: 0.0,
+            status: TransportStatus::Stopped,
+            device_channels: config.channels,
+            device_sample_rate: config.sample_rate,
+            downmix: Downmix::default(),
+            map: None,
+            quality: Quality::default(),
+            resampler: None,
+            looping: None,
+            frame: vec![],
+            fade_frame: vec![],
+            weights: vec![],
+            window: Window::default(),
+            fade_window: Window::default(),
+        }
+    }
+
+    fn update_channel_map(&mut self) {
+        self.map = self.file.as_ref().map(|file| {
+            ChannelMap::new(file.channels as usize, self.device_channels, self.downmix)
+        });
+    }
+
+    fn update_resampler(&mut self) {
+        self.resampler = match &self.file {
+            Some(file) if file.sample_rate != self.device_sample_rate => {
+                Some(Resampler::new(file.sample_rate, self.device_sample_rate, self.quality))
+            },
+            _ => None,
+        };
+    }
+
+    fn process(&mut self, command: Command) {
+        match command {
+            Command::Load(file) => {
+                self.frame = vec![0.0; file.channels as usize];
+                self.fade_frame = vec![0.0; file.channels as usize];
+                self.file = Some(file);
+                self.window = Window::default();
+                self.fade_window = Window::default();
+                self.position = 0.0;
+                self.status = TransportStatus::Stopped;
+                // Positions in the previous file mean nothing in this one
+                self.checkpoints = VecDeque::from(vec![(self.rendered, 0)]);
+                self.update_channel_map();
+                self.update_resampler();
+            },
+            Command::Replace(file) => {
+                self.position = self.position.min(file.frames() as f64);
+                self.file = Some(file);
+                // The windows hold samples from before the edit
+                self.window = Window::default();
+                self.fade_window = Window::default();
+            },
+            Command::Play => {
+                if self.file.is_some() {
+                    self.status = TransportStatus::Playing;
+                }
+            },
+            Command::Pause => {
+                if self.status == TransportStatus::Playing {
+                    self.status = TransportStatus::Paused;
+                }
+            },
+            Command::Stop => {
+                self.position = 0.0;
+                self.status = TransportStatus::Stopped;
+            },
+            Command::SetDownmix(downmix) => {
+                self.downmix = downmix;
+                self.update_channel_map();
+            },
+            Command::SetQuality(quality) => {
+                self.quality = quality;
+                self.update_resampler();
+            },
+            Command::SetLoop(region) => {
+                self.looping = region.filter(|r| r.start < r.end).map(|r| LoopRegion {
+                    // The crossfade overlaps the end of the loop with its beginning, it can't
+                    // take more than half of it.
+                    crossfade: r.crossfade.min((r.end - r.start) / 2),
+                    ..r
+                });
+            },
+        }
+    }
+
+    /// Renders the next frame in the device's channel layout, returns `false` if there is nothing to play.
+    fn next_frame(&mut self, output: &mut [f32]) -> bool {
+        if self.status != TransportStatus::Playing {
+            return false;
+        }
+
+        let (file, map) = match (&self.file, &self.map) {
+            (Some(file), Some(map)) => (file, map),
+            _ => return false,
+        };
+
+        let frames = file.frames() as f64;
+        // A loop that no longer fits in the file, e.g. after an edit, can't be honored.
+        let looping = self.looping.filter(|r| r.end as f64 <= frames);
+
+        if self.position >= frames {
+            self.process(Command::Stop);
+            return false;
+        }
+
+        let resampler = self.resampler.as_ref();
+        read_frame(file, &mut self.window, resampler, &mut self.weights, self.position, &mut self.frame);
+
+        if let Some(region) = looping {
+            let fade_start = (region.end - region.crossfade) as f64;
+            if region.crossfade > 0 && self.position >= fade_start {
+                // Blend the end of the loop with what follows its start, so that jumping back
+                // lands exactly where the fade left off.
+                let head = self.position - (region.end - region.start - region.crossfade) as f64;
+                read_frame(file, &mut self.fade_window, resampler, &mut self.weights, head, &mut self.fade_frame);
+
+                let gain = ((self.position - fade_start) / region.crossfade as f64) as f32;
+                for (s, f) in self.frame.iter_mut().zip(&self.fade_frame) {
+                    *s = *s * (1.0 - gain) + f * gain;
+                }
             }
-            thread::sleep(Duration::from_secs_f32(t_sleep));
         }
-    });
 
-    // Spawn audio device thread.
-    thread::spawn(move || {
+        map.apply(&self.frame, output);
+
+        self.position += resampler.map(|r| r.ratio()).unwrap_or(1.0);
+        if let Some(region) = looping {
+            if self.position >= region.end as f64 {
+                self.position -= (region.end - region.start - region.crossfade) as f64;
+            }
+        }
+        true
+    }
+
+    fn render(&mut self, output: &mut [f32], queued: usize) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.process(command);
+            self.processed += 1;
+        }
+
+        let mut frames = output.chunks_exact_mut(self.device_channels);
+        for frame in &mut frames {
+            if !self.next_frame(frame) {
+                frame.iter_mut().for_each(|s| *s = 0.0);
+                frames.for_each(|f| f.iter_mut().for_each(|s| *s = 0.0));
+                break;
+            }
+        }
+
+        self.rendered += (output.len() / self.device_channels) as u64;
+        self.checkpoints.push_back((self.rendered, self.position as usize));
+        let heard = self.rendered.saturating_sub(queued as u64);
+        while self.checkpoints.len() > 1 && self.checkpoints[1].0 <= heard {
+            self.checkpoints.pop_front();
+        }
+        self.shared_position.store(self.checkpoints[0].1, self.status, self.processed);
+    }
+}
+
+/// Creates a transport whose audio is rendered by `backend`.
+pub fn create_transport<B: AudioBackend>(backend: B) -> Transport {
+    let (sender, receiver) = mpsc::channel();
+    let position = Arc::new(SharedPosition::default());
+
+    let mut player = Player::new(receiver, position.clone(), backend.config());
+    backend.start(Box::new(move |output, queued| player.render(output, queued)));
+
+    Transport { commands: sender, position, sent: Cell::new(0), playing: Cell::new(false) }
+}
+
+/// Preference of the device sample formats, higher is better.
+fn sample_format_rank(format: SampleFormat) -> u8 {
+    match format {
+        SampleFormat::F32 => 2,
+        SampleFormat::I16 => 1,
+        SampleFormat::U16 => 0,
+    }
+}
+
+/// Plays through the default output device of the system.
+pub struct CpalBackend {
+    event_loop: EventLoop,
+    format: Format,
+    buffer_size: usize,
+    dither: Dither,
+}
+
+impl CpalBackend {
+    /// Opens the default device, `dither` is applied when it only accepts integer samples.
+    pub fn new(buffer_size: usize, dither: Dither) -> Result<Self, BackendError> {
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or(BackendError::NoDevice)?;
+
+        let supported_formats_range = device.supported_output_formats()
+            .map_err(|err| BackendError::QueryFormats(err.to_string()))?;
+        let format = supported_formats_range
+            .max_by(|a, b| {
+                sample_format_rank(a.data_type).cmp(&sample_format_rank(b.data_type))
+                    .then_with(|| a.cmp_default_heuristics(b))
+            })
+            .ok_or(BackendError::NoSupportedFormat)?
+            .with_max_sample_rate();
+
+        // Opened here rather than on the device thread, so that a device that can't be used is
+        // reported and another backend picked instead.
         let event_loop = host.event_loop();
-        let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
-        event_loop.play_stream(stream_id).expect("Failed to play_stream.");
-        event_loop.run(move |stream_id, stream_result| {
-            let stream_data = match stream_result {
-                Ok(data) => data,
-                Err(err) => {
-                    eprintln!("An error occurred on stream {:?}: {}", stream_id, err);
-                    return;
-                },
-            };
-
-            match stream_data {
-                StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buffer) } => {
-                    for (i, elem) in buffer.iter_mut().enumerate() {
-                        if i % channels as usize == 0 {
+        let stream_id = event_loop.build_output_stream(&device, &format)
+            .map_err(|err| BackendError::BuildStream(err.to_string()))?;
+        event_loop.play_stream(stream_id)
+            .map_err(|err| BackendError::PlayStream(err.to_string()))?;
+
+        Ok(Self { event_loop, format, buffer_size, dither })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn config(&self) -> OutputConfig {
+        OutputConfig {
+            channels: self.format.channels as usize,
+            sample_rate: self.format.sample_rate.0,
+        }
+    }
+
+    fn start(self, mut render: RenderCallback) {
+        let OutputConfig { channels, sample_rate } = self.config();
+        let Self { event_loop, buffer_size, dither, .. } = self;
+
+        // TODO: Can we know the size of the underlying device buffer?
+        let ring = RingBuffer::<f32>::new(buffer_size * channels);
+        let (mut producer, mut consumer) = ring.split();
+
+        // Spawn audio mixing thread.
+        thread::spawn(move || {
+            let t_sleep = buffer_size as f32 / sample_rate as f32 * 0.5;
+            let mut buffer = vec![0.0; buffer_size * channels];
+            loop {
+                let len = producer.remaining() / channels * channels;
+                if len > 0 {
+                    render(&mut buffer[..len], (producer.len() + len) / channels);
+                    producer.push_slice(&buffer[..len]);
+                }
+                thread::sleep(Duration::from_secs_f32(t_sleep));
+            }
+        });
+
+        // Spawn audio device thread.
+        thread::spawn(move || {
+            let mut quantizer = Quantizer::new(16, dither, NoiseShaping::None, 1);
+            event_loop.run(move |stream_id, stream_result| {
+                let stream_data = match stream_result {
+                    Ok(data) => data,
+                    Err(err) => {
+                        eprintln!("An error occurred on stream {:?}: {}", stream_id, err);
+                        return;
+                    },
+                };
+
+                match stream_data {
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buffer) } => {
+                        for elem in buffer.iter_mut() {
                             *elem = consumer.pop().unwrap_or(0.0);
-                        } else {
-                            *elem = 0.0;
                         }
-                    }
-                },
-                _ => unreachable!(),
-            }
+                    },
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::I16(mut buffer) } => {
+                        for elem in buffer.iter_mut() {
+                            *elem = quantizer.quantize(consumer.pop().unwrap_or(0.0)) as i16;
+                        }
+                    },
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::U16(mut buffer) } => {
+                        for elem in buffer.iter_mut() {
+                            // Unsigned samples are centered around 32768 instead of 0
+                            *elem = (quantizer.quantize(consumer.pop().unwrap_or(0.0)) + 32768) as u16;
+                        }
+                    },
+                    StreamData::Input { .. } => unreachable!(),
+                }
+            });
         });
-    });
+    }
+}
+
+struct NullDevice {
+    render: Option<RenderCallback>,
+    buffer: Vec<f32>,
+    captured: Option<Vec<f32>>,
+    frames_elapsed: u64,
+}
+
+/// Backend without any hardware behind it, buffers are pulled on a simulated clock.
+///
+/// Handles are cheap to clone, keep one around to drive the clock with `advance` and inspect the
+/// rendered output after handing another one to `create_transport`.
+#[derive(Clone)]
+pub struct NullBackend {
+    config: OutputConfig,
+    buffer_size: usize,
+    realtime: bool,
+    device: Arc<Mutex<NullDevice>>,
+}
+
+impl NullBackend {
+    /// Creates a backend whose clock only moves when `advance` is called.
+    pub fn new(config: OutputConfig, buffer_size: usize) -> Self {
+        Self {
+            config,
+            buffer_size,
+            realtime: false,
+            device: Arc::new(Mutex::new(NullDevice {
+                render: None,
+                buffer: vec![0.0; buffer_size * config.channels],
+                captured: None,
+                frames_elapsed: 0,
+            })),
+        }
+    }
+
+    /// Creates a backend that advances on its own at the pace of a real device once started,
+    /// discarding what is rendered.
+    pub fn realtime(config: OutputConfig, buffer_size: usize) -> Self {
+        Self { realtime: true, ..Self::new(config, buffer_size) }
+    }
+
+    /// Keeps a copy of every buffer rendered from now on.
+    pub fn capture(&self) {
+        self.device.lock().unwrap().captured = Some(vec![]);
+    }
+
+    /// Returns the interleaved output captured so far, leaving the capture buffer empty.
+    pub fn take_captured(&self) -> Vec<f32> {
+        let mut device = self.device.lock().unwrap();
+        device.captured.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Pulls `buffers` buffers from the renderer, does nothing until the backend is started.
+    pub fn advance(&self, buffers: usize) {
+        let mut device = self.device.lock().unwrap();
+        let NullDevice { render, buffer, captured, frames_elapsed } = &mut *device;
+        if let Some(render) = render {
+            for _ in 0..buffers {
+                // Buffers are played as soon as they are pulled
+                render(buffer, 0);
+                if let Some(captured) = captured {
+                    captured.extend_from_slice(buffer);
+                }
+                *frames_elapsed += self.buffer_size as u64;
+            }
+        }
+    }
+
+    /// Time elapsed on the simulated clock.
+    pub fn elapsed(&self) -> Duration {
+        let frames = self.device.lock().unwrap().frames_elapsed;
+        Duration::from_secs_f64(frames as f64 / self.config.sample_rate as f64)
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn config(&self) -> OutputConfig {
+        self.config
+    }
+
+    fn start(self, render: RenderCallback) {
+        self.device.lock().unwrap().render = Some(render);
+
+        if self.realtime {
+            let t_sleep = self.buffer_size as f32 / self.config.sample_rate as f32;
+            thread::spawn(move || loop {
+                self.advance(1);
+                thread::sleep(Duration::from_secs_f32(t_sleep));
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_file(samples: Vec<f32>, channels: u16, sample_rate: u32) -> Arc<AudioFile> {
+        Arc::new(AudioFile::from_store(SampleStore::from_planar(samples, channels as usize), sample_rate))
+    }
+
+    fn null_transport(channels: usize, buffer_size: usize) -> (NullBackend, Transport) {
+        let backend = NullBackend::new(OutputConfig { channels, sample_rate: 48000 }, buffer_size);
+        backend.capture();
+        let transport = create_transport(backend.clone());
+        (backend, transport)
+    }
+
+    #[test]
+    fn test_play_to_end() {
+        let (backend, transport) = null_transport(2, 4);
+        transport.load(test_file(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 1, 48000));
+        transport.play();
+
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Playing);
+        assert_eq!(transport.position().position, 4);
+
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Stopped);
+        assert_eq!(backend.take_captured(), [
+            0.1, 0.1, 0.2, 0.2, 0.3, 0.3, 0.4, 0.4,
+            0.5, 0.5, 0.6, 0.6, 0.0, 0.0, 0.0, 0.0,
+        ]);
+        assert_eq!(backend.elapsed(), Duration::from_secs_f64(8.0 / 48000.0));
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let (backend, transport) = null_transport(1, 2);
+        transport.load(test_file(vec![0.1, 0.2, 0.3, 0.4], 1, 48000));
+        transport.play();
+        backend.advance(1);
+
+        transport.pause();
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Paused);
+        assert_eq!(transport.position().position, 2);
+
+        transport.toggle();
+        backend.advance(1);
+        assert_eq!(backend.take_captured(), [0.1, 0.2, 0.0, 0.0, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn test_toggle_before_audio_thread_catches_up() {
+        let (backend, transport) = null_transport(1, 2);
+        transport.load(test_file(vec![0.1, 0.2, 0.3, 0.4], 1, 48000));
+
+        // Nothing was rendered in between, the second press still pauses.
+        transport.toggle();
+        transport.toggle();
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Paused);
+        assert_eq!(backend.take_captured(), [0.0, 0.0]);
+
+        // Once playback stops at the end of the file, toggling starts it again.
+        transport.play();
+        backend.advance(3);
+        assert_eq!(transport.position().status, TransportStatus::Stopped);
+        transport.toggle();
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Playing);
+    }
+
+    #[test]
+    fn test_position_follows_what_is_heard() {
+        let (sender, receiver) = mpsc::channel();
+        let position = Arc::new(SharedPosition::default());
+        let mut player = Player::new(receiver, position.clone(), OutputConfig { channels: 1, sample_rate: 48000 });
+        sender.send(Command::Load(test_file(vec![0.0; 16], 1, 48000))).unwrap();
+        sender.send(Command::Play).unwrap();
+
+        // Two buffers of 4 frames stay queued ahead of the device
+        let mut buffer = [0.0; 4];
+        player.render(&mut buffer, 8);
+        assert_eq!(position.load().position, 0);
+        player.render(&mut buffer, 8);
+        assert_eq!(position.load().position, 0);
+        player.render(&mut buffer, 8);
+        assert_eq!(position.load().position, 4);
+        player.render(&mut buffer, 4);
+        assert_eq!(position.load().position, 12);
+    }
+
+    #[test]
+    fn test_stop_rewinds() {
+        let (backend, transport) = null_transport(1, 2);
+        transport.load(test_file(vec![0.1, 0.2, 0.3, 0.4], 1, 48000));
+        transport.play();
+        backend.advance(1);
+
+        transport.stop();
+        transport.play();
+        backend.advance(1);
+        assert_eq!(backend.take_captured(), [0.1, 0.2, 0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_loop_region() {
+        let (backend, transport) = null_transport(1, 8);
+        transport.load(test_file(vec![0.0, 0.1, 0.2, 0.3, 0.4, 0.5], 1, 48000));
+        transport.set_loop(Some(LoopRegion { start: 1, end: 4, crossfade: 0 }));
+        transport.play();
+
+        backend.advance(1);
+        assert_eq!(transport.position().status, TransportStatus::Playing);
+        assert_eq!(backend.take_captured(), [0.0, 0.1, 0.2, 0.3, 0.1, 0.2, 0.3, 0.1]);
+    }
+
+    #[test]
+    fn test_loop_crossfade() {
+        let (backend, transport) = null_transport(1, 8);
+        transport.load(test_file(vec![1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0], 1, 48000));
+        transport.set_loop(Some(LoopRegion { start: 0, end: 8, crossfade: 4 }));
+        transport.play();
+
+        // The silent tail fades into the beginning of the loop, playback then resumes right
+        // after the part of the beginning that was faded in.
+        backend.advance(2);
+        assert_eq!(backend.take_captured(), [
+            1.0, 1.0, 1.0, 1.0, 0.0, 0.25, 0.5, 0.75,
+            0.0, 0.25, 0.5, 0.75, 0.0, 0.25, 0.5, 0.75,
+        ]);
+    }
 }
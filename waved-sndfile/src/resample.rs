@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+
+/// Number of kernel table entries per zero crossing of the sinc function.
+const OVERSAMPLING: usize = 256;
+
+/// Trade-off between speed and stopband attenuation of the interpolation kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Quality {
+    Fast,
+    #[default]
+    Medium,
+    Best,
+}
+
+impl Quality {
+    /// Zero crossings of the sinc on each side of the kernel.
+    fn zero_crossings(self) -> usize {
+        match self {
+            Quality::Fast => 8,
+            Quality::Medium => 16,
+            Quality::Best => 48,
+        }
+    }
+
+    /// Fraction of the Nyquist frequency kept by the lowpass, leaving room for the transition band.
+    fn rolloff(self) -> f64 {
+        match self {
+            Quality::Fast => 0.85,
+            Quality::Medium => 0.92,
+            Quality::Best => 0.97,
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// 4-term Blackman-Harris window, `x` ranges from -1 to 1.
+fn blackman_harris(x: f64) -> f64 {
+    let phase = PI * (x + 1.0);
+    0.35875 - 0.48829 * phase.cos() + 0.14128 * (2.0 * phase).cos() - 0.01168 * (3.0 * phase).cos()
+}
+
+/// Windowed-sinc sample rate converter.
+///
+/// The signal is reconstructed at arbitrary fractional frame positions, which makes it usable both
+/// for streaming (advance the position by `ratio()` for every output frame) and random access.
+pub struct Resampler {
+    ratio: f64,
+    cutoff: f64,
+    reach: usize,
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, quality: Quality) -> Self {
+        let ratio = from_rate as f64 / to_rate as f64;
+        // When downsampling the lowpass has to move below the target's Nyquist frequency.
+        let cutoff = quality.rolloff() * (to_rate as f64 / from_rate as f64).min(1.0);
+        let zero_crossings = quality.zero_crossings();
+
+        let table = (0..zero_crossings * OVERSAMPLING + 2)
+            .map(|i| {
+                let x = i as f64 / OVERSAMPLING as f64;
+                if x >= zero_crossings as f64 {
+                    0.0
+                } else {
+                    (sinc(x) * blackman_harris(x / zero_crossings as f64)) as f32
+                }
+            })
+            .collect();
+
+        Self {
+            ratio,
+            cutoff,
+            reach: (zero_crossings as f64 / cutoff).ceil() as usize,
+            table,
+        }
+    }
+
+    /// Input frames to advance for every output frame.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    fn kernel(&self, distance: f64) -> f32 {
+        let x = distance.abs() * self.cutoff * OVERSAMPLING as f64;
+        let i = x as usize;
+        if i + 1 >= self.table.len() {
+            return 0.0;
+        }
+
+        let t = (x - i as f64) as f32;
+        (self.table[i] * (1.0 - t) + self.table[i + 1] * t) * self.cutoff as f32
+    }
+
+    /// Fills `weights` with the kernel taps for the fractional frame `position` and returns the
+    /// frame the first tap applies to. The same weights can then be applied to every channel.
+    pub fn weights(&self, position: f64, weights: &mut Vec<f32>) -> isize {
+        let center = position.floor() as isize;
+        let first = center - self.reach as isize + 1;
+        weights.clear();
+        weights.extend((first..=center + self.reach as isize).map(|n| self.kernel(n as f64 - position)));
+        first
+    }
+
+    /// Applies weights returned by `weights` to a channel, frames outside of it are considered silent.
+    pub fn apply(input: &[f32], first: isize, weights: &[f32]) -> f32 {
+        let skip = (-first).max(0) as usize;
+        let start = first.max(0) as usize;
+        weights.iter()
+            .skip(skip)
+            .zip(input.iter().skip(start))
+            .map(|(w, s)| w * s)
+            .sum()
+    }
+
+    /// Reconstructs a single channel at the fractional frame `position`.
+    pub fn interpolate(&self, input: &[f32], position: f64) -> f32 {
+        let mut weights = Vec::with_capacity(self.reach * 2);
+        let first = self.weights(position, &mut weights);
+        Self::apply(input, first, &weights)
+    }
+}
+
+/// Converts planar samples from one sample rate to another.
+pub fn resample(samples: &[f32], channels: usize, from_rate: u32, to_rate: u32, quality: Quality) -> Vec<f32> {
+    assert!(samples.len().is_multiple_of(channels));
+    if from_rate == to_rate {
+        return samples.to_vec();
+    }
+
+    let resampler = Resampler::new(from_rate, to_rate, quality);
+    let frames = samples.len() / channels;
+    let output_frames = (frames as f64 / resampler.ratio()).ceil() as usize;
+
+    let mut resampled = Vec::with_capacity(output_frames * channels);
+    let mut weights = vec![];
+    for channel in samples.chunks(frames.max(1)) {
+        for i in 0..output_frames {
+            let first = resampler.weights(i as f64 * resampler.ratio(), &mut weights);
+            resampled.push(Resampler::apply(channel, first, &weights));
+        }
+    }
+    resampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator;
+
+    #[test]
+    fn test_resample_same_rate() {
+        let data = vec![0.0, 0.5, 1.0, 0.5, 0.0, -0.5];
+        assert_eq!(resample(&data, 2, 44100, 44100, Quality::Best), data);
+    }
+
+    #[test]
+    fn test_resample_sine() {
+        let input: Vec<f32> = generator::sine(44100, 1000.0).take(44100).collect();
+        let output = resample(&input, 1, 44100, 48000, Quality::Best);
+        assert_eq!(output.len(), 48000);
+
+        let expected: Vec<f32> = generator::sine(48000, 1000.0).take(48000).collect();
+        // Skip the edges, where the kernel reaches past the end of the signal
+        for (o, e) in output.iter().zip(&expected).skip(100).take(47800) {
+            assert!((o - e).abs() < 1e-3, "{} != {}", o, e);
+        }
+    }
+}